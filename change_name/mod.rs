@@ -1,50 +1,975 @@
-use crate::{Server_Event, TextMessage, DatabaseUser, V1Client, RepeatedField, future, FutureValue};
+use crate::{Server_Event, TextMessage, DatabaseUser, DatabaseUser_Query, User, User_Query, Channel, V1Client, RepeatedField, future, FutureValue, Persistent};
+use super::Data;
+use super::convenience::{is_admin, error_html, escape_html, base_name, Visibilities};
+use serde_derive::{Serialize, Deserialize};
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
 use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::confusable_detection;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-const KEYWORD: &'static str = "!rename ";
-const NAME_REGEX: &'static str = r"^[\w@\{\}\(\)\|\._-]{1,25}$";
-const INVALID_NICKNAME_MESSAGE: &'static str = "<b><span style=\"color:#aa0000\">Invalid Nickname</span></b>";
+// both commands do the same thing
+const KEYWORDS: [&'static str; 2] = ["!rename", "!nickname"];
+const WHOIS_KEYWORD: &'static str = "!whois";
+const TAG_KEYWORD: &'static str = "!tag";
+const PRONOUNS_KEYWORD: &'static str = "!pronouns";
+const MAX_TAG_LENGTH: usize = 16;
+const NAMES_FILE: &'static str = "names.toml";
+const CONFIG_FILE: &'static str = "change_name.toml";
+const TIME_FORMAT: &'static str = "%Y-%m-%d %H:%M";
+// nobody can take a name that looks like one of these
+const RESERVED_NAMES: [&'static str; 10] = [
+    "admin", "administrator", "moderator", "superuser", "server", "murmur", "mumble", "pelecan", "bot", "root",
+];
 
-pub fn change_name<D>(_t: Arc<Mutex<D>>, c: V1Client, e: Server_Event) -> FutureValue<bool> {
+pub fn change_name(t: Arc<Mutex<Data>>, c: V1Client, e: Server_Event) -> FutureValue<bool> {
     future(async move {
-        if !e.has_user() || !e.has_message() || !e.get_user().has_id() {
+        if !e.has_user() || !e.has_message() {
             return true;
         }
         let user = e.get_user();
-        let id = user.get_id();
         let message = e.get_message();
-        let text = message.get_text().to_owned();
-        let regex = if let Ok(regex) = Regex::new(NAME_REGEX) {
-            regex
-        } else {
-            eprintln!("Invalid regex set in change_name module.");
+        let text = message.get_text().trim().to_owned();
+        let mut words = text.splitn(2, char::is_whitespace);
+        if !words.next().map_or(false, |keyword| KEYWORDS.contains(&keyword)) {
             return true;
+        }
+        let argument = words.next().unwrap_or("").trim().to_owned();
+        let server_path = t.lock().unwrap().path.clone();
+        let policy = {
+            let mut data = t.lock().unwrap();
+            data.volatile_data.name_policy.reload(&server_path);
+            data.volatile_data.name_policy.clone()
         };
 
-        if text.starts_with(KEYWORD) {
-            let new_name = text[KEYWORD.len()..].to_owned();
-            if regex.is_match(&new_name) {
-                // Update the database
-                let mut database_user = DatabaseUser::new();
-                database_user.set_server(e.get_server().to_owned());
-                database_user.set_id(id);
-                if let Ok(u) = c.database_user_get_async(&database_user) {
-                    if let Ok(mut database_user) = u.await {
-                        database_user.set_name(new_name);
-                        database_user.set_id(id);
-                        if let Ok(u) = c.database_user_update_async(&database_user) { drop(u.await); }
-                    } 
+        // unregistered users can only change their name until they disconnect
+        if !user.has_id() {
+            guest_rename(&t, &c, &e, &policy, &argument).await;
+            return false;
+        }
+
+        let id = user.get_id();
+        let config = &policy.config;
+        // only read here, every change goes through `update_records`
+        let records = NameRecords::load(server_path.join(NAMES_FILE));
+
+        let args: Vec<&str> = argument.split_whitespace().collect();
+        match args.as_slice() {
+            ["pending"] | ["approve", _] | ["reject", _] => {
+                if !is_admin(&c, e.get_server(), e.get_user()).await {
+                    send_error(&c, &e, "Only admins can review renames").await;
+                } else if let Err(error) = review_renames(&t, &c, &e, &server_path, &records, &args).await {
+                    send_error(&c, &e, &error).await;
+                }
+                return false;
+            },
+            ["user", old_name, new_name] => {
+                if !is_admin(&c, e.get_server(), e.get_user()).await {
+                    send_error(&c, &e, "Only admins can rename other users").await;
+                } else if let Err(error) = policy.check(new_name) {
+                    send_error(&c, &e, &error).await;
+                } else if let Err(error) = rename_user(&t, &c, &e, &server_path, old_name, new_name).await {
+                    send_error(&c, &e, &error).await;
                 }
+                return false;
+            },
+            _ => {}
+        }
+
+        let new_name = if argument == "reset" {
+            if let Some(original_name) = records.original_names.get(&id.to_string()) {
+                original_name.to_owned()
             } else {
-                let mut error_message = TextMessage::new();
-                error_message.set_server(e.get_server().to_owned());
-                error_message.set_users(RepeatedField::from_vec(vec![user.to_owned()]));
-                error_message.set_text(INVALID_NICKNAME_MESSAGE.to_owned());
-                if let Ok(u) = c.text_message_send_async(&error_message) { drop(u.await); }
+                send_error(&c, &e, "You haven't changed your name").await;
+                return false;
+            }
+        } else if let Err(error) = policy.check(&argument) {
+            send_error(&c, &e, &error).await;
+            return false;
+        } else {
+            argument
+        };
+
+        // only renames the user made themselves count, an admin renaming them shouldn't lock them out
+        if let Some(last_rename) = records.history.iter().rev().find(|rename| rename.id == id && rename.by.is_none()) {
+            let next_rename = last_rename.time + config.cooldown_seconds;
+            if next_rename > unix_time() {
+                send_error(&c, &e, &format!("You can change your name again at {} (UTC)", format_time(next_rename))).await;
+                return false;
+            }
+        }
+
+        if let Err(error) = check_name(&c, &e, &server_path, Some(id), &new_name).await {
+            send_error(&c, &e, &error).await;
+            return false;
+        }
+
+        if config.require_approval {
+            let result = update_records(&t, |records| {
+                records.next_request += 1;
+                // only the most recent request of each user is kept
+                records.pending.retain(|request| request.user_id != id);
+                records.pending.push(PendingRename {
+                    request: records.next_request,
+                    user_id: id,
+                    old_name: user.get_name().to_owned(),
+                    new_name: new_name.clone(),
+                    time: unix_time(),
+                });
+            });
+            if let Err(error) = result {
+                eprintln!("Failed to store the rename request of user {}: {}", id, error);
+            }
+            send_text(&c, &e, vec![user.to_owned()],
+                format!("Your request to be renamed to <b>{}</b> is waiting for an admin to approve it", escape_html(&new_name))).await;
+            return false;
+        }
+
+        let old_name = if let Some(old_name) = rename(&c, &e, id, &new_name).await {
+            old_name
+        } else {
+            send_error(&c, &e, "Your name couldn't be changed").await;
+            return false;
+        };
+        if let Err(error) = update_records(&t, |records| records.record_rename(id, &old_name, &new_name, None)) {
+            eprintln!("Failed to store the rename of user {}: {}", id, error);
+        }
+        move_emote_data(&t, &old_name, &new_name);
+
+        send_text(&c, &e, vec![user.to_owned()], format!("Your name is now <b>{}</b>", escape_html(&new_name))).await;
+        announce(&c, &e, user.get_channel().to_owned(), format!("<b>{}</b> → <b>{}</b>", escape_html(&old_name), escape_html(&new_name))).await;
+        false
+    })
+}
+
+// renames a connected unregistered user without touching the user database. `!rename reset`
+// restores the name they connected with.
+async fn guest_rename(t: &Arc<Mutex<Data>>, c: &V1Client, e: &Server_Event, policy: &NamePolicy, argument: &str) {
+    let user = e.get_user();
+    let session = user.get_session();
+    let original_name = t.lock().unwrap().volatile_data.guest_names.original_names.get(&session).cloned();
+    let new_name = if argument == "reset" {
+        if let Some(original_name) = original_name.clone() {
+            original_name
+        } else {
+            send_error(c, e, "You haven't changed your name").await;
+            return;
+        }
+    } else if let Err(error) = policy.check(argument) {
+        send_error(c, e, &error).await;
+        return;
+    } else {
+        argument.to_owned()
+    };
+    let server_path = t.lock().unwrap().path.clone();
+    if let Err(error) = check_name(c, e, &server_path, None, &new_name).await {
+        send_error(c, e, &error).await;
+        return;
+    }
+
+    let mut live_user = user.to_owned();
+    live_user.set_server(e.get_server().to_owned());
+    live_user.set_name(format!("{}{}", new_name, tag_suffix(user.get_name())));
+    let updated = if let Ok(u) = c.user_update_async(&live_user) { u.await.is_ok() } else { false };
+    if !updated {
+        send_error(c, e, "Your name couldn't be changed").await;
+        return;
+    }
+    {
+        let mut data = t.lock().unwrap();
+        let guest_names = &mut data.volatile_data.guest_names.original_names;
+        if original_name.is_none() {
+            guest_names.insert(session, base_name(user.get_name()).to_owned());
+        } else if argument == "reset" {
+            guest_names.remove(&session);
+        }
+    }
+
+    send_text(c, e, vec![user.to_owned()], format!("Your name is now <b>{}</b> until you disconnect", escape_html(&new_name))).await;
+    announce(c, e, user.get_channel().to_owned(), format!("<b>{}</b> → <b>{}</b>", escape_html(base_name(user.get_name())), escape_html(&new_name))).await;
+}
+
+// forgets the original names of unregistered users and everyone's tags when they disconnect
+pub fn user_disconnected(t: Arc<Mutex<Data>>, _c: V1Client, e: Server_Event) -> FutureValue<bool> {
+    future(async move {
+        if e.has_user() {
+            let session = e.get_user().get_session();
+            let mut data = t.lock().unwrap();
+            data.volatile_data.guest_names.original_names.remove(&session);
+            data.volatile_data.name_tags.sessions.remove(&session);
+        }
+        true
+    })
+}
+
+// the names that unregistered users connected with, by session
+#[derive(Clone)]
+pub struct GuestNames {
+    original_names: HashMap<u32, String>,
+}
+
+impl GuestNames {
+    pub fn new() -> Self {
+        Self {
+            original_names: HashMap::new(),
+        }
+    }
+}
+
+// handles `!tag` and `!pronouns`, which decorate the displayed name of a user without changing the
+// name they are registered with
+pub fn tag(t: Arc<Mutex<Data>>, c: V1Client, e: Server_Event) -> FutureValue<bool> {
+    future(async move {
+        if !e.has_user() || !e.has_message() {
+            return true;
+        }
+        let user = e.get_user();
+        let text = e.get_message().get_text().trim().to_owned();
+        let mut words = text.splitn(2, char::is_whitespace);
+        let keyword = words.next().unwrap_or("");
+        if keyword != TAG_KEYWORD && keyword != PRONOUNS_KEYWORD {
+            return true;
+        }
+        let argument = words.next().unwrap_or("").trim();
+        let session = user.get_session();
+        let mut tags = t.lock().unwrap().volatile_data.name_tags.sessions.get(&session).cloned().unwrap_or_default();
+
+        let mut args = argument.splitn(2, char::is_whitespace);
+        let result = match (keyword, args.next().unwrap_or(""), args.next().map(|arg| arg.trim())) {
+            (TAG_KEYWORD, "afk", None) => {
+                tags.afk = !tags.afk;
+                Ok(())
+            },
+            (TAG_KEYWORD, "role", None) => {
+                tags.role = None;
+                Ok(())
+            },
+            (TAG_KEYWORD, "role", Some(role)) => check_tag(role).map(|_| tags.role = Some(role.to_owned())),
+            (TAG_KEYWORD, "clear", None) => {
+                tags.afk = false;
+                tags.role = None;
+                Ok(())
+            },
+            (PRONOUNS_KEYWORD, "clear", None) => {
+                tags.pronouns = None;
+                Ok(())
+            },
+            (PRONOUNS_KEYWORD, pronouns, None) if !pronouns.is_empty() => check_tag(pronouns).map(|_| tags.pronouns = Some(pronouns.to_owned())),
+            _ => {
+                send_text(&c, &e, vec![user.to_owned()], "<br/><h3>🏷️ Tag commands:</h3><ul> \
+                          <li><tt>!tag afk</tt><br/>(Mark yourself as away. Typing it again removes the mark.)</li> \
+                          <li><tt>!tag role <b>role</b></tt><br/>(Show a role next to your name. Typing <tt>!tag role</tt> removes it.)</li> \
+                          <li><tt>!tag clear</tt><br/>(Remove your away mark and role.)</li> \
+                          <li><tt>!pronouns <b>pronouns</b></tt><br/>(Show your pronouns next to your name. Typing <tt>!pronouns clear</tt> removes them.)</li></ul>".to_string()).await;
+                return false;
             }
+        };
+        if let Err(error) = result {
+            send_error(&c, &e, &error).await;
             return false;
         }
+
+        if user.has_id() {
+            // roles and pronouns are kept for the next time the user connects
+            let result = update_records(&t, |records| {
+                records.tags.insert(user.get_id().to_string(), tags.clone());
+                records.tags.retain(|_, tags| tags.role.is_some() || tags.pronouns.is_some());
+            });
+            if let Err(error) = result {
+                eprintln!("Failed to store the tags of user {}: {}", user.get_id(), error);
+            }
+        }
+        t.lock().unwrap().volatile_data.name_tags.sessions.insert(session, tags.clone());
+
+        let name = format!("{}{}", base_name(user.get_name()), tags.suffix());
+        if apply_name(&c, &e, user.to_owned(), &name).await {
+            send_text(&c, &e, vec![user.to_owned()], format!("Your name is now shown as <b>{}</b>", escape_html(&name))).await;
+        } else {
+            send_error(&c, &e, "Your tags couldn't be changed").await;
+        }
+        false
+    })
+}
+
+// delivers the notices that registered users missed while they were offline and shows their saved
+// role and pronouns when they connect
+pub fn user_connected(t: Arc<Mutex<Data>>, c: V1Client, e: Server_Event) -> FutureValue<bool> {
+    future(async move {
+        if !e.has_user() || !e.get_user().has_id() {
+            return true;
+        }
+        let user = e.get_user();
+        let key = user.get_id().to_string();
+        let server_path = t.lock().unwrap().path.clone();
+        let records = NameRecords::load(server_path.join(NAMES_FILE));
+        if records.notices.contains_key(&key) {
+            match update_records(&t, |records| records.notices.remove(&key)) {
+                Ok(Some(notices)) => send_text(&c, &e, vec![user.to_owned()], notices.join("<br/>")).await,
+                Ok(None) => {},
+                Err(error) => eprintln!("Failed to store the delivered notices of user {}: {}", user.get_id(), error),
+            }
+        }
+        if let Some(tags) = records.tags.get(&key) {
+            t.lock().unwrap().volatile_data.name_tags.sessions.insert(user.get_session(), tags.clone());
+            let name = format!("{}{}", base_name(user.get_name()), tags.suffix());
+            apply_name(&c, &e, user.to_owned(), &name).await;
+        }
         true
     })
 }
+
+// changes the name of a connected user without touching the user database
+async fn apply_name(c: &V1Client, e: &Server_Event, mut user: User, name: &str) -> bool {
+    user.set_server(e.get_server().to_owned());
+    user.set_name(name.to_owned());
+    if let Ok(u) = c.user_update_async(&user) {
+        return u.await.is_ok();
+    }
+    false
+}
+
+fn check_tag(tag: &str) -> Result<(), String> {
+    if tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(format!("Tags can't be longer than {} characters", MAX_TAG_LENGTH));
+    }
+    if !tag.chars().all(|c| c.is_alphanumeric() || c == ' ' || c == '/' || c == '-') {
+        return Err("Tags can only contain letters, digits, spaces, / and -".to_string());
+    }
+    Ok(())
+}
+
+// the tags at the end of a displayed name
+fn tag_suffix(name: &str) -> &str {
+    &name[base_name(name).len()..]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Tags {
+    // being away is only shown until the user disconnects
+    #[serde(skip)]
+    afk: bool,
+    role: Option<String>,
+    pronouns: Option<String>,
+}
+
+impl Tags {
+    fn suffix(&self) -> String {
+        let mut suffix = String::new();
+        if self.afk {
+            suffix.push_str(" [AFK]");
+        }
+        if let Some(role) = self.role.as_ref() {
+            suffix.push_str(&format!(" [{}]", role));
+        }
+        if let Some(pronouns) = self.pronouns.as_ref() {
+            suffix.push_str(&format!(" ({})", pronouns));
+        }
+        suffix
+    }
+}
+
+// the tags of connected users, by session
+#[derive(Clone)]
+pub struct NameTags {
+    sessions: HashMap<u32, Tags>,
+}
+
+impl NameTags {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+// handles `!rename user <old name> <new name>`, which admins use to rename other registered users
+async fn rename_user(t: &Arc<Mutex<Data>>, c: &V1Client, e: &Server_Event, server_path: &PathBuf, old_name: &str, new_name: &str) -> Result<(), String> {
+    let registered_users = registered_users(c, e).await.ok_or("Couldn't look up the user".to_string())?;
+    let target = registered_users.iter().find(|user| user.get_name() == old_name)
+        .or_else(|| registered_users.iter().find(|user| user.get_name().to_lowercase() == old_name.to_lowercase()))
+        .ok_or(format!("There is no registered user called {}", escape_html(old_name)))?;
+    let target_id = target.get_id();
+
+    check_name(c, e, server_path, Some(target_id), new_name).await?;
+    let old_name = rename(c, e, target_id, new_name).await.ok_or("The name couldn't be changed".to_string())?;
+    update_records(t, |records| records.record_rename(target_id, &old_name, new_name, Some(e.get_user().get_id())))?;
+    move_emote_data(t, &old_name, new_name);
+
+    let target = online_user(c, e, target_id).await;
+    if let Some(target) = target.as_ref() {
+        announce(c, e, target.get_channel().to_owned(), format!("<b>{}</b> → <b>{}</b>", escape_html(&old_name), escape_html(new_name))).await;
+    }
+    notify(t, c, e, target_id, target, format!("An admin changed your name to <b>{}</b>", escape_html(new_name))).await;
+    send_text(c, e, vec![e.get_user().to_owned()], format!("Renamed <b>{}</b> to <b>{}</b>", escape_html(&old_name), escape_html(new_name))).await;
+    Ok(())
+}
+
+// handles `!rename pending`, `!rename approve <request>` and `!rename reject <request>`
+async fn review_renames(t: &Arc<Mutex<Data>>, c: &V1Client, e: &Server_Event, server_path: &PathBuf, records: &NameRecords, args: &[&str]) -> Result<(), String> {
+    if args == ["pending"] {
+        let list = if records.pending.is_empty() {
+            "There are no pending renames".to_string()
+        } else {
+            records.pending.iter().fold("<h3>📝 Pending renames:</h3>".to_string(), |mut list, request| {
+                list.push_str(&format!("[{}] {} → <b>{}</b> ({})<br/>",
+                    request.request, escape_html(&request.old_name), escape_html(&request.new_name), format_time(request.time)));
+                list
+            })
+        };
+        send_text(c, e, vec![e.get_user().to_owned()], list).await;
+        return Ok(());
+    }
+
+    let number = args[1].parse::<u32>().map_err(|_| format!("{} is not a request number", escape_html(args[1])))?;
+    let request = records.pending.iter().find(|request| request.request == number)
+        .ok_or(format!("There is no pending rename with the number {}", number))?.clone();
+    let requester = online_user(c, e, request.user_id).await;
+
+    let notice = if args[0] == "approve" {
+        check_name(c, e, server_path, Some(request.user_id), &request.new_name).await?;
+        let old_name = rename(c, e, request.user_id, &request.new_name).await
+            .ok_or("The name couldn't be changed".to_string())?;
+        update_records(t, |records| {
+            records.pending.retain(|pending| pending.request != number);
+            records.record_rename(request.user_id, &old_name, &request.new_name, Some(e.get_user().get_id()));
+        })?;
+        move_emote_data(t, &old_name, &request.new_name);
+        if let Some(requester) = requester.as_ref() {
+            announce(c, e, requester.get_channel().to_owned(), format!("<b>{}</b> → <b>{}</b>", escape_html(&old_name), escape_html(&request.new_name))).await;
+        }
+        format!("Your rename to <b>{}</b> was approved", escape_html(&request.new_name))
+    } else {
+        update_records(t, |records| records.pending.retain(|pending| pending.request != number))?;
+        format!("Your rename to <b>{}</b> was rejected", escape_html(&request.new_name))
+    };
+    notify(t, c, e, request.user_id, requester, notice).await;
+    send_text(c, e, vec![e.get_user().to_owned()], format!("Request {} was {}", number, if args[0] == "approve" { "approved" } else { "rejected" })).await;
+    Ok(())
+}
+
+// makes sure that a name can't be mistaken for the name of a reserved name or another user,
+// registered or not. `id` is the user id of the user that is renamed, or None for the unregistered
+// user that sent the message.
+async fn check_name(c: &V1Client, e: &Server_Event, server_path: &PathBuf, id: Option<u32>, name: &str) -> Result<(), String> {
+    let name_skeleton = skeleton(name);
+    if RESERVED_NAMES.iter().any(|reserved| skeleton(reserved) == name_skeleton) {
+        return Err(format!("{} is a reserved name", escape_html(name)));
+    }
+
+    let registered_users = registered_users(c, e).await
+        .ok_or("Couldn't check whether the name is taken".to_string())?;
+    // the names of users who hid themselves from listings are not revealed, so every name is
+    // paired with whether it can be shown to the sender
+    let visibilities = Visibilities::read(server_path);
+    let viewer = if e.get_user().has_id() { Some(e.get_user().get_id()) } else { None };
+    let mut names: Vec<(String, bool)> = registered_users.iter()
+        .filter(|user| Some(user.get_id()) != id)
+        .map(|user| (user.get_name().to_owned(), visibilities.is_visible_to(user.get_id(), viewer)))
+        .collect();
+
+    let mut query = User_Query::new();
+    query.set_server(e.get_server().to_owned());
+    if let Ok(u) = c.user_query_async(&query) {
+        if let Ok(online_users) = u.await {
+            names.extend(online_users.get_users().iter()
+                .filter(|user| match id {
+                    Some(id) => !user.has_id() || user.get_id() != id,
+                    None => user.get_session() != e.get_user().get_session(),
+                })
+                // tags are not part of anyone's identity
+                .map(|user| (base_name(user.get_name()).to_owned(), true)));
+        }
+    }
+
+    for (other, shown) in names.iter() {
+        if other == name {
+            return Err(format!("{} is already taken", escape_html(name)));
+        } else if skeleton(other) == name_skeleton {
+            if *shown {
+                return Err(format!("{} is too similar to {}", escape_html(name), escape_html(other)));
+            }
+            return Err(format!("{} is too similar to the name of another user", escape_html(name)));
+        }
+    }
+    Ok(())
+}
+
+// a form of the name in which characters that look alike are the same, so that names which only
+// differ in such characters are detected. Compatibility forms like fullwidth letters are folded by
+// NFKC normalization and everything else is mapped to its prototype from the unicode confusables
+// table (UTS #39). Prototypes of more than one character, like `rn` for `m`, are not used since they
+// would make unrelated names like `claire` and `daire` collide.
+fn skeleton(name: &str) -> String {
+    let lowercase: String = name.nfkc().flat_map(char::to_lowercase).collect();
+    lowercase.nfd()
+        .flat_map(|c| {
+            let mut prototype = confusable_detection::skeleton(c.encode_utf8(&mut [0; 4])).collect::<String>();
+            if prototype.chars().count() != 1 {
+                prototype = c.to_string();
+            }
+            // prototypes are not always lowercase, e.g. `0` looks like `O`
+            prototype.to_lowercase().chars().collect::<Vec<char>>()
+        })
+        .filter(|c| !matches!(c, '_' | '-' | '.' | ' '))
+        .collect()
+}
+
+// the connected user with a user id, if they are online
+async fn online_user(c: &V1Client, e: &Server_Event, id: u32) -> Option<User> {
+    let mut query = User_Query::new();
+    query.set_server(e.get_server().to_owned());
+    let mut list = c.user_query_async(&query).ok()?.await.ok()?;
+    list.take_users().into_iter().find(|user| user.has_id() && user.get_id() == id)
+}
+
+async fn registered_users(c: &V1Client, e: &Server_Event) -> Option<Vec<DatabaseUser>> {
+    let mut query = DatabaseUser_Query::new();
+    query.set_server(e.get_server().to_owned());
+    let mut list = c.database_user_query_async(&query).ok()?.await.ok()?;
+    Some(list.take_users().into_vec())
+}
+
+// changes the registered name of a user and their name if they are connected. Returns the old
+// registered name.
+async fn rename(c: &V1Client, e: &Server_Event, id: u32, new_name: &str) -> Option<String> {
+    let mut database_user = DatabaseUser::new();
+    database_user.set_server(e.get_server().to_owned());
+    database_user.set_id(id);
+    let mut database_user = c.database_user_get_async(&database_user).ok()?.await.ok()?;
+    let old_name = database_user.get_name().to_owned();
+    database_user.set_server(e.get_server().to_owned());
+    database_user.set_name(new_name.to_owned());
+    database_user.set_id(id);
+    c.database_user_update_async(&database_user).ok()?.await.ok()?;
+
+    if let Some(mut user) = online_user(c, e, id).await {
+        let name = format!("{}{}", new_name, tag_suffix(user.get_name()));
+        user.set_server(e.get_server().to_owned());
+        user.set_name(name);
+        if let Ok(u) = c.user_update_async(&user) { drop(u.await); }
+    }
+    Some(old_name)
+}
+
+async fn announce(c: &V1Client, e: &Server_Event, channel: Channel, text: String) {
+    let mut message = TextMessage::new();
+    message.set_server(e.get_server().to_owned());
+    message.set_channels(RepeatedField::from_vec(vec![channel]));
+    message.set_text(text);
+    if let Ok(u) = c.text_message_send_async(&message) { drop(u.await); }
+}
+
+async fn send_text(c: &V1Client, e: &Server_Event, users: Vec<User>, text: String) {
+    let mut message = TextMessage::new();
+    message.set_server(e.get_server().to_owned());
+    message.set_users(RepeatedField::from_vec(users));
+    message.set_text(text);
+    if let Ok(u) = c.text_message_send_async(&message) { drop(u.await); }
+}
+
+// sends a notice to a registered user, or keeps it until they connect if they are offline
+async fn notify(t: &Arc<Mutex<Data>>, c: &V1Client, e: &Server_Event, id: u32, user: Option<User>, notice: String) {
+    match user {
+        Some(user) => send_text(c, e, vec![user], notice).await,
+        None => {
+            if let Err(error) = update_records(t, |records| records.notices.entry(id.to_string()).or_default().push(notice)) {
+                eprintln!("Failed to store a notice for user {}: {}", id, error);
+            }
+        },
+    }
+}
+
+// loads names.toml, changes it and stores it again while holding the data lock, so that commands
+// running at the same time can't overwrite each other's changes with a stale copy. Nothing can be
+// awaited inside `update`.
+fn update_records<T>(t: &Arc<Mutex<Data>>, update: impl FnOnce(&mut NameRecords) -> T) -> Result<T, String> {
+    let data = t.lock().unwrap();
+    let path = data.path.join(NAMES_FILE);
+    let mut records = NameRecords::load(&path);
+    let result = update(&mut records);
+    records.store(&path).map_err(|error| error.to_string())?;
+    Ok(result)
+}
+
+// emote preferences and statistics are kept by name, so they have to be moved along with the user.
+// Guests aren't renamed for good, so this is only done for registered users.
+fn move_emote_data(t: &Arc<Mutex<Data>>, old_name: &str, new_name: &str) {
+    let data = t.lock().unwrap();
+    super::emotes::user_renamed(&data.path, old_name, new_name);
+}
+
+async fn send_error(c: &V1Client, e: &Server_Event, error: &str) {
+    send_text(c, e, vec![e.get_user().to_owned()], error_html(error)).await;
+}
+
+// shows the previous names of the users that have or had a name. Admins can also look up who had
+// a name at a certain time with `!whois <name> at <time>`.
+pub fn whois(t: Arc<Mutex<Data>>, c: V1Client, e: Server_Event) -> FutureValue<bool> {
+    future(async move {
+        if !e.has_user() || !e.has_message() {
+            return true;
+        }
+        let text = e.get_message().get_text().trim().to_owned();
+        let mut words = text.splitn(2, char::is_whitespace);
+        if words.next() != Some(WHOIS_KEYWORD) {
+            return true;
+        }
+        let argument = words.next().unwrap_or("").trim();
+        let (name, time) = match argument.rfind(" at ") {
+            Some(index) => (argument[..index].trim(), Some(argument[(index + 4)..].trim())),
+            None => (argument, None),
+        };
+        if name.is_empty() {
+            send_error(&c, &e, "Usage: <tt>!whois &lt;name&gt;</tt>").await;
+            return false;
+        }
+
+        let server_path = t.lock().unwrap().path.clone();
+        let records = NameRecords::load(server_path.join(NAMES_FILE));
+        let registered_users = registered_users(&c, &e).await.unwrap_or_default();
+        let current_names: HashMap<u32, String> = registered_users.iter()
+            .map(|user| (user.get_id(), user.get_name().to_owned()))
+            .collect();
+
+        let reply = if let Some(time) = time {
+            if !is_admin(&c, e.get_server(), e.get_user()).await {
+                send_error(&c, &e, "Only admins can look up who had a name at a certain time").await;
+                return false;
+            }
+            let time = if let Some(time) = parse_time(time) {
+                time
+            } else {
+                send_error(&c, &e, "Times have to look like <tt>2020-12-03 14:36</tt> or <tt>2020-12-03</tt> (UTC)").await;
+                return false;
+            };
+            let holders: Vec<String> = records.user_ids(&current_names).into_iter()
+                .filter(|id| records.name_at(*id, time, &current_names).map_or(false, |n| n.to_lowercase() == name.to_lowercase()))
+                .map(|id| display_name(id, &current_names))
+                .collect();
+            if holders.is_empty() {
+                format!("Nobody was called <b>{}</b> at {}", escape_html(name), format_time(time))
+            } else {
+                format!("<b>{}</b> was held by {} at {}", escape_html(name), holders.join(", "), format_time(time))
+            }
+        } else {
+            // users who hid themselves from listings are left out, except for admins
+            let visibilities = Visibilities::read(&server_path);
+            let viewer = if e.get_user().has_id() { Some(e.get_user().get_id()) } else { None };
+            let mut ids: Vec<u32> = records.user_ids(&current_names).into_iter()
+                .filter(|id| records.names(*id, &current_names).iter().any(|n| n.to_lowercase() == name.to_lowercase()))
+                .collect();
+            if ids.iter().any(|id| !visibilities.is_visible_to(*id, viewer)) && !is_admin(&c, e.get_server(), e.get_user()).await {
+                ids.retain(|id| visibilities.is_visible_to(*id, viewer));
+            }
+            if ids.is_empty() {
+                format!("Nobody has been called <b>{}</b>", escape_html(name))
+            } else {
+                ids.into_iter().fold(String::new(), |mut reply, id| {
+                    reply.push_str(&format!("<h3>🪪 {}</h3>", display_name(id, &current_names)));
+                    let renames: Vec<&Rename> = records.history.iter().filter(|rename| rename.id == id).collect();
+                    if renames.is_empty() {
+                        reply.push_str("Never changed their name<br/>");
+                    }
+                    for rename in renames {
+                        reply.push_str(&format!("{}: {} → {}",
+                            format_time(rename.time), escape_html(&rename.old_name), escape_html(&rename.new_name)));
+                        if let Some(admin) = rename.by {
+                            reply.push_str(&format!(" (by {})", display_name(admin, &current_names)));
+                        }
+                        reply.push_str("<br/>");
+                    }
+                    reply
+                })
+            }
+        };
+        send_text(&c, &e, vec![e.get_user().to_owned()], reply).await;
+        false
+    })
+}
+
+fn display_name(id: u32, current_names: &HashMap<u32, String>) -> String {
+    match current_names.get(&id) {
+        Some(name) => format!("<b>{}</b>", escape_html(name)),
+        None => format!("<i>unregistered user #{}</i>", id),
+    }
+}
+
+fn parse_time(time: &str) -> Option<u64> {
+    let time = NaiveDateTime::parse_from_str(time, TIME_FORMAT)
+        .or_else(|_| NaiveDate::parse_from_str(time, "%Y-%m-%d").map(|date| date.and_hms(0, 0, 0)))
+        .ok()?;
+    Some(time.timestamp() as u64)
+}
+
+fn format_time(time: u64) -> String {
+    Utc.timestamp(time as i64, 0).format(TIME_FORMAT).to_string()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct NameRecords {
+    // the names users were registered with before they first changed them, by user id
+    original_names: HashMap<String, String>,
+    // every rename, oldest first
+    history: Vec<Rename>,
+    // renames that are waiting for an admin to approve them
+    pending: Vec<PendingRename>,
+    // number of the most recent rename request
+    next_request: u32,
+    // roles and pronouns of registered users, by user id
+    tags: HashMap<String, Tags>,
+    // notices for users that were offline when something happened to their name, by user id
+    notices: HashMap<String, Vec<String>>,
+}
+
+impl Persistent for NameRecords {}
+
+impl NameRecords {
+    // records a rename of a user, `by` is the id of the admin that renamed or approved it
+    fn record_rename(&mut self, id: u32, old_name: &str, new_name: &str, by: Option<u32>) {
+        // the original registration name is kept so that `!rename reset` can restore it
+        if !self.original_names.contains_key(&id.to_string()) {
+            self.original_names.insert(id.to_string(), old_name.to_owned());
+        }
+        self.history.push(Rename {
+            id: id,
+            old_name: old_name.to_owned(),
+            new_name: new_name.to_owned(),
+            time: unix_time(),
+            by: by,
+        });
+    }
+
+    // ids of all users that are registered or have changed their name
+    fn user_ids(&self, current_names: &HashMap<u32, String>) -> Vec<u32> {
+        let mut ids: Vec<u32> = current_names.keys().cloned()
+            .chain(self.history.iter().map(|rename| rename.id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    // every name a user has had
+    fn names(&self, id: u32, current_names: &HashMap<u32, String>) -> Vec<String> {
+        self.history.iter()
+            .filter(|rename| rename.id == id)
+            .flat_map(|rename| vec![rename.old_name.clone(), rename.new_name.clone()])
+            .chain(current_names.get(&id).cloned())
+            .collect()
+    }
+
+    // the name a user had at a certain time
+    fn name_at(&self, id: u32, time: u64, current_names: &HashMap<u32, String>) -> Option<String> {
+        let renames: Vec<&Rename> = self.history.iter().filter(|rename| rename.id == id).collect();
+        if let Some(rename) = renames.iter().rev().find(|rename| rename.time <= time) {
+            Some(rename.new_name.clone())
+        } else if let Some(rename) = renames.first() {
+            Some(rename.old_name.clone())
+        } else {
+            current_names.get(&id).cloned()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PendingRename {
+    request: u32,
+    user_id: u32,
+    old_name: String,
+    new_name: String,
+    // unix time in seconds
+    time: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NameConfig {
+    // users have to wait this long after changing their name before they can change it again
+    cooldown_seconds: u64,
+    // renames have to be approved by an admin with `!rename approve <request>`
+    require_approval: bool,
+    // length limits in characters
+    min_length: usize,
+    max_length: usize,
+    // scripts that letters in names may come from, e.g. "latin", "cyrillic" or "cjk". Letters from
+    // any script are allowed if this is empty.
+    allowed_scripts: Vec<String>,
+    // characters other than letters and digits that are allowed
+    allowed_symbols: String,
+    // whether names may contain single spaces between words
+    allow_spaces: bool,
+    // names may not contain any of these, this also catches names that only look like they do
+    banned_substrings: Vec<String>,
+    // an additional regular expression that names have to match, ignored if empty
+    pattern: String,
+}
+
+impl Persistent for NameConfig {}
+
+impl std::default::Default for NameConfig {
+    fn default() -> Self {
+        Self {
+            cooldown_seconds: 60 * 60,
+            require_approval: false,
+            min_length: 1,
+            max_length: 25,
+            allowed_scripts: vec![],
+            allowed_symbols: "@{}()|._-".to_owned(),
+            allow_spaces: false,
+            banned_substrings: vec![],
+            pattern: String::new(),
+        }
+    }
+}
+
+// the name policy from the configuration file, compiled when it is loaded
+#[derive(Clone)]
+pub struct NamePolicy {
+    config: NameConfig,
+    pattern: Option<Regex>,
+    // skeletons of the banned substrings
+    banned_skeletons: Vec<String>,
+    modified: Option<SystemTime>,
+}
+
+impl NamePolicy {
+    pub fn new() -> Self {
+        Self::compile(NameConfig::default())
+    }
+
+    fn compile(config: NameConfig) -> Self {
+        let pattern = if config.pattern.is_empty() {
+            None
+        } else {
+            match Regex::new(&config.pattern) {
+                Ok(regex) => Some(regex),
+                Err(_) => {
+                    eprintln!("Invalid name pattern set in {}, it is ignored.", CONFIG_FILE);
+                    None
+                }
+            }
+        };
+        Self {
+            pattern: pattern,
+            banned_skeletons: config.banned_substrings.iter().map(|banned| skeleton(banned)).collect(),
+            config: config,
+            modified: None,
+        }
+    }
+
+    // loads the configuration file if it has changed since it was last read
+    pub fn reload(&mut self, server_path: &PathBuf) {
+        let path = server_path.join(CONFIG_FILE);
+        let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+        // loading creates the file with the default settings if it doesn't exist yet
+        *self = Self::compile(NameConfig::load(&path));
+        self.modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+    }
+
+    // explains which rule a name breaks, if any
+    fn check(&self, name: &str) -> Result<(), String> {
+        let config = &self.config;
+        let length = name.chars().count();
+        if length < config.min_length || length > config.max_length {
+            return Err(format!("Names have to be between {} and {} characters long", config.min_length, config.max_length));
+        }
+        for c in name.chars() {
+            if c.is_whitespace() {
+                if !config.allow_spaces || c != ' ' {
+                    return Err("Names can't contain spaces".to_string());
+                }
+            } else if c.is_alphabetic() {
+                if !config.allowed_scripts.is_empty() && !script(c).map_or(false, |s| config.allowed_scripts.iter().any(|a| a == s)) {
+                    return Err(format!("{} is not from one of the allowed scripts ({})",
+                        escape_html(&c.to_string()), config.allowed_scripts.join(", ")));
+                }
+            } else if !c.is_numeric() && !config.allowed_symbols.contains(c) {
+                return Err(format!("{} is not allowed in names, the only symbols allowed are {}",
+                    escape_html(&c.to_string()), escape_html(&config.allowed_symbols)));
+            }
+        }
+        if name.starts_with(' ') || name.ends_with(' ') || name.contains("  ") {
+            return Err("Names can't start or end with a space or contain several spaces in a row".to_string());
+        }
+        if base_name(name) != name {
+            return Err("Names can't end with something that looks like a tag".to_string());
+        }
+        let name_skeleton = skeleton(name);
+        if self.banned_skeletons.iter().any(|banned| !banned.is_empty() && name_skeleton.contains(banned.as_str())) {
+            return Err("That name contains a banned word".to_string());
+        }
+        if let Some(pattern) = self.pattern.as_ref() {
+            if !pattern.is_match(name) {
+                return Err(format!("Names have to match <tt>{}</tt>", escape_html(pattern.as_str())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// the script that a letter belongs to
+fn script(c: char) -> Option<&'static str> {
+    let script = match c as u32 {
+        0x0041..=0x007A | 0x00C0..=0x024F | 0x1E00..=0x1EFF => "latin",
+        0x0370..=0x03FF | 0x1F00..=0x1FFF => "greek",
+        0x0400..=0x052F => "cyrillic",
+        0x0530..=0x058F => "armenian",
+        0x0590..=0x05FF => "hebrew",
+        0x0600..=0x06FF | 0x0750..=0x077F => "arabic",
+        0x0900..=0x097F => "devanagari",
+        0x0E00..=0x0E7F => "thai",
+        0x10A0..=0x10FF => "georgian",
+        0x1100..=0x11FF | 0xAC00..=0xD7AF => "hangul",
+        0x3040..=0x309F => "hiragana",
+        0x30A0..=0x30FF => "katakana",
+        0x3400..=0x4DBF | 0x4E00..=0x9FFF => "cjk",
+        _ => return None,
+    };
+    Some(script)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Rename {
+    id: u32,
+    old_name: String,
+    new_name: String,
+    // unix time in seconds
+    time: u64,
+    // the admin that renamed the user or approved the rename
+    #[serde(default)]
+    by: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::skeleton;
+
+    #[test]
+    fn lookalike_names_have_the_same_skeleton() {
+        assert_eq!(skeleton("Admin"), skeleton("admin"));
+        assert_eq!(skeleton("ａｄｍｉｎ"), skeleton("admin"));
+        assert_eq!(skeleton("admın"), skeleton("admin"));
+        assert_eq!(skeleton("аdmin"), skeleton("admin"));
+        assert_eq!(skeleton("adm1n_"), skeleton("admln"));
+        assert_eq!(skeleton("b0b"), skeleton("bob"));
+    }
+
+    #[test]
+    fn different_names_have_different_skeletons() {
+        assert_ne!(skeleton("claire"), skeleton("daire"));
+        assert_ne!(skeleton("corn"), skeleton("com"));
+        assert_ne!(skeleton("alice"), skeleton("bob"));
+    }
+}
@@ -0,0 +1,123 @@
+use crate::{V1Client, Server, User, Channel, ACL_Query, ACL_Permission, Persistent};
+use serde_derive::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+const ROOT_CHANNEL: u32 = 0;
+// profiles of registered users, which are edited with `!profile set` of the list_users module
+pub const PROFILES_FILE: &'static str = "profiles.toml";
+
+// admins are users who are allowed to change the permissions of the root channel. This is for modules
+// that use the V1Client api, list_users has its own version for the tonic client.
+pub async fn is_admin(c: &V1Client, server: &Server, user: &User) -> bool {
+    let mut root = Channel::new();
+    root.set_id(ROOT_CHANNEL);
+    let mut query = ACL_Query::new();
+    query.set_server(server.to_owned());
+    query.set_user(user.to_owned());
+    query.set_channel(root);
+    if let Ok(u) = c.acl_get_effective_permissions_async(&query) {
+        if let Ok(acl) = u.await {
+            return acl.get_allow() & (ACL_Permission::Write as u32) != 0;
+        }
+    }
+    false
+}
+
+// formats an error the same way in every module
+pub fn error_html<S: AsRef<str>>(error: S) -> String {
+    format!("<b><span style=\"color:#aa0000\">{}</span></b>", error.as_ref())
+}
+
+// makes text that users typed safe to put into an html message, including inside attributes
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// strips the tags that the change_name module appends to displayed names, like `[AFK]`, so that
+// a user is recognized no matter which tags they currently have
+pub fn base_name(name: &str) -> &str {
+    let mut name = name;
+    loop {
+        let tag_start = if name.ends_with(']') {
+            name.rfind(" [")
+        } else if name.ends_with(')') {
+            name.rfind(" (")
+        } else {
+            None
+        };
+        match tag_start {
+            Some(index) => name = &name[..index],
+            None => return name,
+        }
+    }
+}
+
+// who can find a registered user in listings of the user database
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Visibility {
+    Public,
+    RegisteredOnly,
+    Hidden,
+}
+
+impl std::default::Default for Visibility {
+    fn default() -> Self {
+        Visibility::Public
+    }
+}
+
+impl Visibility {
+    // whether the user with the given id can be found by a viewer, who is None if they are not
+    // registered. Everyone can find themselves, no matter their visibility.
+    pub fn is_visible_to(self, id: u32, viewer: Option<u32>) -> bool {
+        viewer == Some(id) || match self {
+            Visibility::Public => true,
+            Visibility::RegisteredOnly => viewer.is_some(),
+            Visibility::Hidden => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::RegisteredOnly => "registered-only",
+            Visibility::Hidden => "hidden",
+        }
+    }
+}
+
+// the visibility of every registered user, read from their profiles. Admins can see everyone, so
+// callers have to check that themselves.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Visibilities {
+    // by user id
+    users: HashMap<String, ProfileVisibility>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct ProfileVisibility {
+    visibility: Visibility,
+}
+
+impl Persistent for Visibilities {}
+
+impl Visibilities {
+    pub fn read(server_path: &PathBuf) -> Self {
+        Self::load(server_path.join(PROFILES_FILE))
+    }
+
+    pub fn is_visible_to(&self, id: u32, viewer: Option<u32>) -> bool {
+        self.users.get(&id.to_string())
+            .map(|profile| profile.visibility)
+            .unwrap_or_default()
+            .is_visible_to(id, viewer)
+    }
+}
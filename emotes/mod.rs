@@ -1,73 +1,296 @@
-use std::collections::{VecDeque, HashMap};
-use crate::{V1Client, future, FutureValue, TextMessage_Filter};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use crate::{V1Client, Server_Event, future, FutureValue, User, User_Query, Channel_Query, TextMessage, TextMessage_Filter, TextMessage_Filter_Action, RepeatedField, Persistent};
 use std::sync::{Arc, Mutex};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant, SystemTime};
 use super::Data;
+use super::convenience::{is_admin, error_html, escape_html, base_name};
+use serde_derive::{Serialize, Deserialize};
 use regex::Regex;
 use std::process::Command;
 
-// emotes are cached so that using the same emote multiple times in a message does not cause that
-// many reads from the filesystem to happen.
-const MAX_CACHED_EMOTES: usize = 10;
-// since each image is inlined as base64 encoded data, you should set a cap on how
-// many emotes can be in a message to combat spam
-const MAX_EMOTES_PER_MESSAGE: usize = 5;
-// emote height in pixels
-const EMOTE_HEIGHT: u8 = 25;
-const EMOTE_DELIMITER: char = ':';
-// restrictions on what constitutes a valid emote name
-const EMOTE_REGEX: &'static str = r"^[a-z0-9]+$";
+// the configuration is stored in the server's data directory and is reloaded whenever the file
+// changes, so none of these settings require a rebuild of the server.
+const CONFIG_FILE: &'static str = "emotes.toml";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct EmoteConfig {
+    // emotes are cached so that using the same emote multiple times in a message does not cause that
+    // many reads from the filesystem to happen. The cache is capped by the total size of the cached
+    // emotes rather than by their number because a single large emote can dwarf ten small ones.
+    // Names that don't belong to any emote are cached as well and count towards this limit.
+    max_cache_bytes: usize,
+    // since each image is inlined as base64 encoded data, you should set a cap on how
+    // many emotes can be in a message to combat spam
+    max_emotes_per_message: usize,
+    // emote height in pixels
+    emote_height: u32,
+    emote_delimiter: char,
+    // restrictions on what constitutes a valid emote name
+    emote_regex: String,
+    // whether emote files or the built in emoji shortcodes are used when both have the same name
+    shortcode_precedence: Precedence,
+    // if not empty, only these shortcodes are available
+    shortcode_allow: Vec<String>,
+    // shortcodes that are never substituted
+    shortcode_deny: Vec<String>,
+    // images imported from emote packs are shrunk to this height in pixels
+    import_height: u32,
+    // users can't send more than this many emotes within `rate_limit_seconds`, 0 disables the limit
+    rate_limit_emotes: usize,
+    rate_limit_seconds: u64,
+    // drop messages that consist of nothing but at least `repeated_emote_threshold` of the same emote
+    drop_repeated_emotes: bool,
+    repeated_emote_threshold: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Precedence {
+    Files,
+    Shortcodes,
+}
+
+impl EmoteConfig {
+    fn shortcode(&self, name: &str) -> Option<&'static str> {
+        if (!self.shortcode_allow.is_empty() && !self.shortcode_allow.iter().any(|n| n == name))
+            || self.shortcode_deny.iter().any(|n| n == name) {
+            return None;
+        }
+        SHORTCODES.binary_search_by_key(&name, |&(shortcode, _)| shortcode)
+            .ok()
+            .map(|index| SHORTCODES[index].1)
+    }
+}
+
+impl Persistent for EmoteConfig {}
+
+impl std::default::Default for EmoteConfig {
+    fn default() -> Self {
+        Self {
+            max_cache_bytes: 512 * 1024,
+            max_emotes_per_message: 5,
+            emote_height: 25,
+            emote_delimiter: ':',
+            emote_regex: r"^[a-z0-9]+$".to_owned(),
+            shortcode_precedence: Precedence::Files,
+            shortcode_allow: vec![],
+            shortcode_deny: vec![],
+            import_height: 64,
+            rate_limit_emotes: 20,
+            rate_limit_seconds: 60,
+            drop_repeated_emotes: false,
+            repeated_emote_threshold: 3,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmoteSettings {
+    config: EmoteConfig,
+    regex: Regex,
+    modified: Option<SystemTime>,
+}
+
+impl EmoteSettings {
+    pub fn new() -> Self {
+        let config = EmoteConfig::default();
+        Self {
+            regex: Regex::new(&config.emote_regex).unwrap(),
+            config: config,
+            modified: None,
+        }
+    }
+
+    // loads the configuration file if it has changed since it was last read. Returns true if the
+    // settings were (re)loaded.
+    pub fn reload(&mut self, server_path: &PathBuf) -> bool {
+        let path = server_path.join(CONFIG_FILE);
+        let modified = modified_time(&path);
+        if modified.is_some() && modified == self.modified {
+            return false;
+        }
+        // loading creates the file with the default settings if it doesn't exist yet
+        let config = EmoteConfig::load(&path);
+        self.modified = modified_time(&path);
+        match Regex::new(&config.emote_regex) {
+            Ok(regex) => {
+                self.regex = regex;
+                self.config = config;
+            },
+            Err(_) => eprintln!("Emote regex in {} is not valid, keeping the previous settings.", path.display())
+        }
+        true
+    }
+}
+
+fn modified_time(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
 
+#[derive(Clone)]
+pub enum Emote {
+    // base64 encoded png data, which is inlined as an image with the height that the recipient prefers
+    Image(String),
+    // text that may contain placeholders, see `render_template`
+    Text(String),
+}
+
+impl Emote {
+    fn len(&self) -> usize {
+        match self {
+            Emote::Image(base64) => base64.len(),
+            Emote::Text(template) => template.len(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct CachedEmote {
+    // None if there is no emote with this name, so that unknown names don't cause a read from the
+    // filesystem every time they are used
+    emote: Option<Emote>,
+    // the emote file, or the emote directory for unknown names. Its modification time is compared to
+    // the cached one to detect emotes that were replaced, added or removed since they were cached.
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl CachedEmote {
+    fn size(&self, name: &str) -> usize {
+        name.len() + self.emote.as_ref().map_or(0, |emote| emote.len())
+    }
+
+    fn is_stale(&self) -> bool {
+        modified_time(&self.path) != self.modified
+    }
+}
+
+// least recently used cache of emotes that is capped by the total size of its entries in bytes
 #[derive(Clone)]
 pub struct EmoteCache {
-    cached_emotes: VecDeque<String>,
-    base64_cache: HashMap<String, String>
+    entries: HashMap<String, CachedEmote>,
+    // maps the time an emote was last used to its name, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    cached_bytes: usize,
+    // aliases and deprecated names from the manifest, mapped to the names they refer to
+    aliases: HashMap<String, String>,
+    // emotes that admins have banned, from the manifest
+    banned: Vec<String>,
+    manifest_modified: Option<SystemTime>,
 }
 
 impl EmoteCache {
     pub fn new() -> Self {
         Self {
-            cached_emotes: VecDeque::new(),
-            base64_cache: HashMap::new(),
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            cached_bytes: 0,
+            aliases: HashMap::new(),
+            banned: vec![],
+            manifest_modified: None,
         }
     }
 
-    pub fn get_emote(&mut self, name: &str, dir: &PathBuf) -> Option<String> {
-        if self.base64_cache.contains_key(name) {
-            // move the emote to the front of the cache
-            self.cache(name.to_owned(), String::with_capacity(0));
-            Some(self.base64_cache.get(name).unwrap().to_string())
-        } else if let Some(base64) = load_emote(dir, name) {
-            self.cache(name.to_owned(), base64.clone());
-            Some(base64)
-        } else {
-            None
+    // returns the name of the emote that an alias or deprecated name refers to
+    pub fn resolve_name(&mut self, name: &str, dir: &PathBuf) -> String {
+        let manifest_path = dir.join(MANIFEST_FILE);
+        let modified = modified_time(&manifest_path);
+        if modified != self.manifest_modified {
+            self.manifest_modified = modified;
+            let manifest = if modified.is_some() {
+                Manifest::load(&manifest_path)
+            } else {
+                Manifest::default()
+            };
+            self.aliases = manifest.aliases();
+            self.banned = manifest.banned;
+        }
+        let mut name = name.to_owned();
+        // deprecated names may redirect to names that were renamed again later, but don't follow
+        // them forever in case they form a loop
+        for _ in 0..MAX_REDIRECTS {
+            match self.aliases.get(&name) {
+                Some(target) => name = target.to_owned(),
+                None => break,
+            }
         }
+        name
     }
 
-    fn cache(&mut self, name: String, base64: String) {
-        if let Some(index) = self.cached_emotes.iter().position(|c| c == &name) {
-            self.cached_emotes.remove(index);
-        } else {
-            self.base64_cache.insert(name.clone(), base64);
-            if self.cached_emotes.len() > MAX_CACHED_EMOTES {
-                if let Some(c) = self.cached_emotes.pop_back() {
-                    self.base64_cache.remove(&c);
-                }
+    // whether an emote was banned, aliases have to be resolved first
+    pub fn is_banned(&self, name: &str) -> bool {
+        self.banned.iter().any(|banned| banned == name)
+    }
+
+    pub fn get_emote(&mut self, name: &str, dir: &PathBuf, config: &EmoteConfig) -> Option<Emote> {
+        let name = &self.resolve_name(name, dir);
+        self.clock += 1;
+        if let Some(entry) = self.entries.get_mut(name) {
+            if !entry.is_stale() {
+                // move the emote to the front of the cache
+                self.recency.remove(&entry.last_used);
+                self.recency.insert(self.clock, name.to_owned());
+                entry.last_used = self.clock;
+                return entry.emote.clone();
             }
+            self.remove(name);
+        }
+        let (emote, path) = match load_emote(dir, name) {
+            Some((emote, path)) => (Some(emote), path),
+            None => (None, dir.to_owned())
+        };
+        let entry = CachedEmote {
+            emote: emote.clone(),
+            modified: modified_time(&path),
+            path: path,
+            last_used: self.clock,
+        };
+        self.insert(name.to_owned(), entry, config.max_cache_bytes);
+        emote
+    }
+
+    fn insert(&mut self, name: String, entry: CachedEmote, max_bytes: usize) {
+        let size = entry.size(&name);
+        // emotes that are larger than the whole cache are not worth caching
+        if size > max_bytes {
+            return;
+        }
+        while self.cached_bytes + size > max_bytes {
+            let oldest = if let Some((_, name)) = self.recency.iter().next() {
+                name.to_owned()
+            } else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+        self.cached_bytes += size;
+        self.recency.insert(entry.last_used, name.clone());
+        self.entries.insert(name, entry);
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.recency.remove(&entry.last_used);
+            self.cached_bytes -= entry.size(name);
         }
-        self.cached_emotes.push_front(name);
     }
 }
 
-fn load_emote(dir: &PathBuf, name: &str) -> Option<String> {
+fn load_emote(dir: &PathBuf, name: &str) -> Option<(Emote, PathBuf)> {
     let png_path = dir.join(format!("{}.png", name));
     if png_path.exists() {
-        return Some(image_html(base64_encode(&png_path)?));
+        return Some((Emote::Image(base64_encode(&png_path)?), png_path));
     }
     let txt_path = dir.join(format!("{}.txt", name));
     if txt_path.exists() {
-        return Some(String::from_utf8_lossy(&std::fs::read(txt_path).ok()?).to_string());
+        return Some((Emote::Text(String::from_utf8_lossy(&std::fs::read(&txt_path).ok()?).to_string()), txt_path));
     }
     None
 }
@@ -85,71 +308,1469 @@ fn base64_encode(path: &PathBuf) -> Option<String> {
     None
 }
 
-fn image_html(base64: String) -> String {
-    format!("<img height=\"{}\" src=\"data:image/png;base64,{}\"/>", EMOTE_HEIGHT, base64)
+fn image_html(base64: &str, height: u32) -> String {
+    format!("<img height=\"{}\" src=\"data:image/png;base64,{}\"/>", height, base64)
 }
 
 
-pub fn chat_filter(t: Arc<Mutex<Data>>, _c: V1Client, mut filter: TextMessage_Filter) -> FutureValue<(bool, TextMessage_Filter)> {
-    future(async move {
-        let dir = t.lock().unwrap().path.join("emotes");
-        let emote_cache = &mut t.lock().unwrap().volatile_data.emote_cache;
+enum Token<'a, T> {
+    Text(&'a str),
+    Emote(&'a str, T),
+}
+
+// splits a message into plain text and the emotes that `resolve` returns something for. Everything
+// is sliced at byte offsets returned by `find` so that messages with any kind of unicode text
+// around the emotes are handled correctly.
+fn tokenize<'a, T, F: FnMut(&str) -> Option<T>>(text: &'a str, delimiter: char, mut resolve: F) -> Vec<Token<'a, T>> {
+    let mut tokens = vec![];
+    let mut text_start = 0;
+    let mut search_start = 0;
+    while let Some(offset) = text[search_start..].find(delimiter) {
+        let open = search_start + offset;
+        if text[..open].ends_with('\\') {
+            // an escaped delimiter is printed without the backslash and can't start an emote
+            if open - 1 > text_start {
+                tokens.push(Token::Text(&text[text_start..(open - 1)]));
+            }
+            text_start = open;
+            search_start = open + delimiter.len_utf8();
+            continue;
+        }
+        let name_start = open + delimiter.len_utf8();
+        let close = if let Some(offset) = text[name_start..].find(delimiter) {
+            name_start + offset
+        } else {
+            // an unterminated delimiter is just text
+            break;
+        };
+        let name = &text[name_start..close];
+        if let Some(emote) = resolve(name) {
+            if open > text_start {
+                tokens.push(Token::Text(&text[text_start..open]));
+            }
+            tokens.push(Token::Emote(name, emote));
+            text_start = close + delimiter.len_utf8();
+            search_start = text_start;
+        } else {
+            // the closing delimiter may still open an emote, e.g. in "at 10:30 :smile:"
+            search_start = close;
+        }
+    }
+    if text_start < text.len() {
+        tokens.push(Token::Text(&text[text_start..]));
+    }
+    tokens
+}
+
+// emotes are not substituted inside these tags because they contain links or code that users paste
+const VERBATIM_TAGS: [&'static str; 4] = ["a", "tt", "pre", "code"];
 
+// splits an html message into pieces, each marked with whether emotes may be substituted in it.
+// Tags, the contents of verbatim tags and urls are all left alone.
+fn html_segments(text: &str) -> Vec<(&str, bool)> {
+    let mut segments = vec![];
+    let mut verbatim_depth = 0usize;
+    let mut start = 0;
+    while start < text.len() {
+        let rest = &text[start..];
+        if rest.starts_with('<') {
+            let end = rest.find('>').map_or(rest.len(), |index| index + 1);
+            let tag = &rest[..end];
+            let closing = tag.starts_with("</");
+            let name: String = tag.trim_start_matches(|c| c == '<' || c == '/')
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            if VERBATIM_TAGS.contains(&name.as_str()) {
+                if closing {
+                    verbatim_depth = verbatim_depth.saturating_sub(1);
+                } else if !tag.ends_with("/>") {
+                    verbatim_depth += 1;
+                }
+            }
+            segments.push((tag, false));
+            start += end;
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            let text_node = &rest[..end];
+            if verbatim_depth > 0 {
+                segments.push((text_node, false));
+            } else {
+                segments.append(&mut url_segments(text_node));
+            }
+            start += end;
+        }
+    }
+    segments
+}
+
+// splits a text node into urls, which are marked as not substitutable, and the text around them
+fn url_segments(text: &str) -> Vec<(&str, bool)> {
+    let mut segments = vec![];
+    let mut text_start = 0;
+    let mut word_start = 0;
+    for (index, chr) in text.char_indices().chain(std::iter::once((text.len(), ' '))) {
+        if !chr.is_whitespace() {
+            continue;
+        }
+        let word = &text[word_start..index];
+        if word.contains("://") || word.starts_with("www.") {
+            if word_start > text_start {
+                segments.push((&text[text_start..word_start], true));
+            }
+            segments.push((word, false));
+            text_start = index;
+        }
+        word_start = index + chr.len_utf8();
+    }
+    if text_start < text.len() {
+        segments.push((&text[text_start..], true));
+    }
+    segments
+}
+
+// splits an emote like `say(hello, world)` into its name and arguments
+fn parse_invocation(invocation: &str) -> Option<(&str, Vec<&str>)> {
+    if let Some(open) = invocation.find('(') {
+        if !invocation.ends_with(')') {
+            return None;
+        }
+        let args = &invocation[(open + 1)..(invocation.len() - 1)];
+        Some((&invocation[..open], args.split(',').map(|arg| arg.trim()).collect()))
+    } else {
+        Some((invocation, vec![]))
+    }
+}
+
+// information about a message that text emotes can refer to
+struct TemplateContext {
+    sender: String,
+    // only looked up when a text emote uses `{channel}`, since that needs a request to the server
+    channel: Option<String>,
+}
+
+// fills in the placeholders of a text emote. `{1}`, `{2}`, ... are replaced with the arguments,
+// `{args}` with all of them, `{sender}` with the name of the user that sent the message and
+// `{channel}` with the name of the channel it was sent to. Everything that is filled in is escaped.
+fn render_template(template: &str, args: &[&str], context: &TemplateContext) -> String {
+    // arguments come from the html of the message, so they have to be unescaped first to avoid
+    // escaping entities twice
+    let args: Vec<String> = args.iter().map(|arg| escape_html(&unescape_html(arg))).collect();
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let close = if let Some(close) = rest[open..].find('}') {
+            open + close
+        } else {
+            break;
+        };
+        rendered.push_str(&rest[..open]);
+        let placeholder = &rest[(open + 1)..close];
+        match placeholder {
+            "sender" => rendered.push_str(&escape_html(&context.sender)),
+            "channel" => rendered.push_str(&escape_html(context.channel.as_deref().unwrap_or(""))),
+            "args" => rendered.push_str(&args.join(" ")),
+            _ => if let Ok(index) = placeholder.parse::<usize>() {
+                if index > 0 {
+                    if let Some(arg) = args.get(index - 1) {
+                        rendered.push_str(arg);
+                    }
+                }
+            } else {
+                // not a placeholder
+                rendered.push_str(&rest[open..=close]);
+            }
+        }
+        rest = &rest[(close + 1)..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+// how image emotes are shown to a recipient
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Variant {
+    // images with the given height in pixels
+    Images(u32),
+    // the names of the emotes instead of images
+    TextOnly,
+}
+
+struct Rendered {
+    text: String,
+    // names of the emotes that were substituted
+    used_emotes: Vec<String>,
+    // names of banned emotes that were left as they were
+    banned_emotes: Vec<String>,
+    // whether the message consists of nothing but emotes
+    only_emotes: bool,
+    // whether a text emote uses the name of the channel, which was not known yet
+    needs_channel: bool,
+}
+
+impl Rendered {
+    // whether the message is nothing but the same emote over and over
+    fn is_repeated_emote_spam(&self, config: &EmoteConfig) -> bool {
+        self.only_emotes && self.used_emotes.iter().any(|name| {
+            self.used_emotes.iter().filter(|n| *n == name).count() >= config.repeated_emote_threshold
+        })
+    }
+}
+
+// substitutes the emotes in an html message
+fn render_message(text: &str, dir: &PathBuf, settings: &EmoteSettings, emote_cache: &mut EmoteCache, context: &TemplateContext, variant: Variant) -> Rendered {
+    let config = &settings.config;
+    let mut used_emotes: Vec<String> = vec![];
+    let mut banned_emotes: Vec<String> = vec![];
+    let mut needs_channel = false;
+    let mut resolve = |invocation: &str| {
+        // stop loading emotes once the message is over the limit since it won't be changed anyway
+        if used_emotes.len() > config.max_emotes_per_message {
+            return None;
+        }
+        let (name, args) = parse_invocation(invocation)?;
+        let shortcode = if args.is_empty() { config.shortcode(name) } else { None };
+        // resolving the name also reloads the manifest, which the bans are read from
+        let resolved_name = emote_cache.resolve_name(name, dir);
+        if shortcode.is_some() && config.shortcode_precedence == Precedence::Shortcodes {
+            // bans apply to shortcodes as well as emote files
+            if emote_cache.is_banned(name) {
+                banned_emotes.push(name.to_owned());
+                return None;
+            }
+            used_emotes.push(name.to_owned());
+            return shortcode.map(|emoji| emoji.to_owned());
+        }
+        let emote = if settings.regex.is_match(name) {
+            emote_cache.get_emote(name, dir, config)
+        } else {
+            None
+        };
+        // usage is recorded for the emote that aliases and deprecated names refer to
+        let name = if emote.is_some() { resolved_name } else { name.to_owned() };
+        if (emote.is_some() || shortcode.is_some()) && emote_cache.is_banned(&name) {
+            banned_emotes.push(name);
+            return None;
+        }
+        let html = match emote {
+            Some(Emote::Image(base64)) if args.is_empty() => match variant {
+                Variant::Images(height) => image_html(&base64, height),
+                Variant::TextOnly => format!("{}{}{}", config.emote_delimiter, invocation, config.emote_delimiter),
+            },
+            Some(Emote::Text(template)) => {
+                needs_channel |= context.channel.is_none() && template.contains("{channel}");
+                render_template(&template, &args, context)
+            },
+            // images don't take arguments
+            Some(Emote::Image(_)) => return None,
+            // fall back to the emoji if there is no emote file with this name
+            None => shortcode?.to_owned(),
+        };
+        used_emotes.push(name);
+        Some(html)
+    };
+
+    let mut new_message = String::with_capacity(text.len());
+    let mut only_emotes = true;
+    for (segment, substitute) in html_segments(text) {
+        if !substitute {
+            only_emotes &= segment.starts_with('<');
+            new_message.push_str(segment);
+            continue;
+        }
+        for token in tokenize(segment, config.emote_delimiter, &mut resolve) {
+            match token {
+                Token::Text(text) => {
+                    only_emotes &= text.trim().is_empty();
+                    new_message.push_str(text);
+                },
+                Token::Emote(_, emote) => new_message.push_str(&emote),
+            }
+        }
+    }
+
+    Rendered {
+        text: new_message,
+        only_emotes: only_emotes && !used_emotes.is_empty(),
+        used_emotes: used_emotes,
+        banned_emotes: banned_emotes,
+        needs_channel: needs_channel,
+    }
+}
+
+pub fn chat_filter(t: Arc<Mutex<Data>>, c: V1Client, mut filter: TextMessage_Filter) -> FutureValue<(bool, TextMessage_Filter)> {
+    future(async move {
         if !filter.has_message() || !filter.get_message().has_text() {
             return (true, filter)
         }
 
         let text = filter.get_message().get_text().to_owned();
+        let server_path = t.lock().unwrap().path.clone();
+        let dir = server_path.join("emotes");
 
         if std::fs::create_dir_all(&dir).is_err() {
             return (true, filter);
         }
 
-        let regex = if let Ok(regex) = Regex::new(EMOTE_REGEX) {
-            regex
-        } else {
-            eprintln!("Emote regex is not valid!");
-            return (true, filter);
+        let settings = {
+            let mut data = t.lock().unwrap();
+            data.volatile_data.emote_settings.reload(&server_path);
+            data.volatile_data.emote_settings.clone()
         };
 
-        let mut text_iter = text.chars().enumerate();
-        let mut new_message = String::with_capacity(text.len());
-        let mut start_index = 0;
-        let mut num_emotes = 0;
-
-        while let Some((index, chr)) = text_iter.next() {
-            // iterate until we find the emote delimiter
-            if chr == EMOTE_DELIMITER {
-                new_message.push_str(&text[start_index..index]);
-                start_index = index;
-                // iterate until we find a closing delimiter
-                while let Some((index, chr)) = text_iter.next() {
-                    if chr == EMOTE_DELIMITER {
-                        let emote_name = &text[(start_index + 1)..index];
-                        if regex.is_match(emote_name) {
-                            num_emotes += 1;
-                            if let Some(emote) = emote_cache.get_emote(emote_name, &dir) {
-                                new_message.push_str(&emote);
-                            }
-                            start_index = index + 1;
-                        } else {
-                            new_message.push_str(&text[start_index..(index+1)]);
-                        }
-                        break;
-                    }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if words.first() == Some(&COMMAND) {
+            filter.set_action(TextMessage_Filter_Action::Drop);
+            let is_admin = if words.len() > 1 && ADMIN_COMMANDS.contains(&words[1]) {
+                is_admin(&c, filter.get_server(), filter.get_message().get_actor()).await
+            } else {
+                false
+            };
+            let reply = emote_command(&server_path, &settings, &user_key(filter.get_message().get_actor().get_name()), is_admin, &words[1..]);
+            send_reply(&c, &filter, reply).await;
+            return (false, filter);
+        }
+
+        let sender = filter.get_message().get_actor().get_name().to_owned();
+        let session = filter.get_message().get_actor().get_session();
+        let channel_id = filter.get_message().get_channels().first().map(|channel| channel.get_id());
+
+        if let Some(invocation) = reaction_emote(&text, settings.config.emote_delimiter) {
+            let channel_id = if let Some(channel_id) = channel_id {
+                channel_id
+            } else {
+                filter.set_action(TextMessage_Filter_Action::Drop);
+                send_reply(&c, &filter, "<b>Reactions can only be sent to channels</b>".to_string()).await;
+                return (false, filter);
+            };
+            let mut context = TemplateContext {
+                sender: sender.clone(),
+                channel: None,
+            };
+            let delimiter = settings.config.emote_delimiter;
+            let emote = format!("{}{}{}", delimiter, invocation, delimiter);
+            let variant = Variant::Images(settings.config.emote_height);
+            let mut rendered = {
+                let mut data = t.lock().unwrap();
+                render_message(&emote, &dir, &settings, &mut data.volatile_data.emote_cache, &context, variant)
+            };
+            if rendered.needs_channel {
+                context.channel = Some(channel_name(&c, &filter).await);
+                let mut data = t.lock().unwrap();
+                rendered = render_message(&emote, &dir, &settings, &mut data.volatile_data.emote_cache, &context, variant);
+            }
+            let reaction = {
+                let mut data = t.lock().unwrap();
+                if !rendered.banned_emotes.is_empty() {
+                    Err("This emote is banned".to_string())
+                } else if rendered.used_emotes.len() != 1 {
+                    Err("Unknown emote".to_string())
+                } else if let Err(wait) = data.volatile_data.emote_rate_limiter.check(session, 1, &settings.config) {
+                    Err(rate_limit_notice(wait))
+                } else {
+                    let used_emotes = rendered.used_emotes;
+                    data.volatile_data.emote_reactions.react(channel_id, &sender, &used_emotes[0], rendered.text)
+                        .map(|line| (line, used_emotes))
+                        .ok_or("There is no message to react to".to_string())
                 }
-                if num_emotes > MAX_EMOTES_PER_MESSAGE {
-                    break;
+            };
+            return match reaction {
+                Ok((line, used_emotes)) => {
+                    record_usage(&t, &user_key(&sender), &used_emotes);
+                    let mut text_message = filter.get_message().to_owned();
+                    text_message.set_text(line);
+                    filter.set_message(text_message);
+                    (true, filter)
+                },
+                Err(error) => {
+                    filter.set_action(TextMessage_Filter_Action::Drop);
+                    send_reply(&c, &filter, error_html(error)).await;
+                    (false, filter)
                 }
+            };
+        }
+
+        if !text.contains(settings.config.emote_delimiter) {
+            if let Some(channel_id) = channel_id {
+                t.lock().unwrap().volatile_data.emote_reactions.message_sent(channel_id, &sender, &text);
             }
+            return (true, filter);
+        }
+
+        let mut context = TemplateContext {
+            sender: sender.clone(),
+            channel: None,
+        };
+        let config = &settings.config;
+        let default_variant = Variant::Images(config.emote_height);
+        let mut rendered = {
+            let mut data = t.lock().unwrap();
+            render_message(&text, &dir, &settings, &mut data.volatile_data.emote_cache, &context, default_variant)
+        };
+        if rendered.needs_channel {
+            context.channel = Some(channel_name(&c, &filter).await);
+            let mut data = t.lock().unwrap();
+            rendered = render_message(&text, &dir, &settings, &mut data.volatile_data.emote_cache, &context, default_variant);
         }
-        new_message.push_str(&text[start_index..]);
 
-        if num_emotes <= MAX_EMOTES_PER_MESSAGE && num_emotes != 0 {
+        if config.drop_repeated_emotes && rendered.is_repeated_emote_spam(config) {
+            filter.set_action(TextMessage_Filter_Action::Drop);
+            send_reply(&c, &filter, error_html("Your message was not sent because it only consists of the same emote over and over".to_string())).await;
+            return (false, filter);
+        }
+
+        // only messages that are actually sent can be reacted to
+        if let Some(channel_id) = channel_id {
+            t.lock().unwrap().volatile_data.emote_reactions.message_sent(channel_id, &sender, &text);
+        }
+
+        // explain to the sender why emotes were not shown
+        let mut notices = vec![];
+        if !rendered.banned_emotes.is_empty() {
+            notices.push(format!("These emotes are banned: {}", rendered.banned_emotes.join(", ")));
+        }
+        let too_many = rendered.used_emotes.len() > config.max_emotes_per_message;
+        let rate_limited = if too_many {
+            notices.push(format!("Emotes are only shown in messages with at most {} emotes", config.max_emotes_per_message));
+            false
+        } else if rendered.used_emotes.is_empty() {
+            false
+        } else if let Err(wait) = t.lock().unwrap().volatile_data.emote_rate_limiter.check(session, rendered.used_emotes.len(), config) {
+            notices.push(rate_limit_notice(wait));
+            true
+        } else {
+            false
+        };
+        if !notices.is_empty() {
+            send_reply(&c, &filter, error_html(notices.join("<br/>"))).await;
+        }
+        if too_many || rate_limited || rendered.text == text {
+            return (true, filter);
+        }
+
+        let new_message = rendered.text;
+        record_usage(&t, &user_key(&sender), &rendered.used_emotes);
+
+        // since the filter can only change the message for everyone, the message is dropped and all
+        // recipients are sent their own version of it as soon as one of them has different preferences
+        let preferences = EmotePreferences::load(server_path.join(PREFERENCES_FILE));
+        let recipients = if preferences.users.values().any(|p| p.variant(&settings.config) != default_variant) {
+            recipients(&c, &filter).await
+        } else {
+            None
+        };
+        let mut groups: HashMap<Variant, Vec<User>> = HashMap::new();
+        for user in recipients.unwrap_or_default() {
+            let variant = preferences.variant(&user_key(user.get_name()), &settings.config);
+            groups.entry(variant).or_default().push(user);
+        }
+
+        if groups.is_empty() || (groups.len() == 1 && groups.contains_key(&default_variant)) {
             let mut text_message = filter.get_message().to_owned();
             text_message.set_text(new_message);
             filter.set_message(text_message);
+            return (true, filter);
+        }
+
+        let messages: Vec<TextMessage> = {
+            let mut data = t.lock().unwrap();
+            groups.into_iter().map(|(variant, users)| {
+                let variant_text = if variant == default_variant {
+                    new_message.clone()
+                } else {
+                    render_message(&text, &dir, &settings, &mut data.volatile_data.emote_cache, &context, variant).text
+                };
+                let mut message = filter.get_message().to_owned();
+                message.set_server(filter.get_server().to_owned());
+                message.set_users(RepeatedField::from_vec(users));
+                message.clear_channels();
+                message.clear_trees();
+                message.set_text(variant_text);
+                message
+            }).collect()
+        };
+        for message in messages {
+            if let Ok(u) = c.text_message_send_async(&message) { drop(u.await); }
+        }
+        filter.set_action(TextMessage_Filter_Action::Drop);
+        (false, filter)
+    })
+}
+
+// preferences and statistics are kept by user name since the user id is not available to chat
+// filters. Tags in the name, like `[AFK]`, are left out so that they don't change who a user is.
+fn user_key(name: &str) -> String {
+    base_name(name).to_owned()
+}
+
+// called by the change_name module when a registered user is renamed, so that their preferences and
+// statistics follow them and whoever takes their old name later doesn't inherit them. Anything that
+// was still kept for the new name is dropped. The caller has to hold the data lock.
+pub fn user_renamed(server_path: &PathBuf, old_name: &str, new_name: &str) {
+    let (old_key, new_key) = (user_key(old_name), user_key(new_name));
+    if old_key == new_key {
+        return;
+    }
+    let path = server_path.join(PREFERENCES_FILE);
+    let mut preferences = EmotePreferences::load(&path);
+    if move_entry(&mut preferences.users, &old_key, &new_key) {
+        if let Err(e) = preferences.store(&path) {
+            eprintln!("Failed to store emote preferences: {}", e);
+        }
+    }
+    let path = server_path.join(STATS_FILE);
+    let mut stats = EmoteStats::load(&path);
+    if move_entry(&mut stats.users, &old_key, &new_key) {
+        if let Err(e) = stats.store(&path) {
+            eprintln!("Failed to store emote statistics: {}", e);
+        }
+    }
+}
+
+// returns whether the map was changed
+fn move_entry<V>(map: &mut HashMap<String, V>, from: &str, to: &str) -> bool {
+    match map.remove(from) {
+        Some(value) => {
+            map.insert(to.to_owned(), value);
+            true
+        },
+        None => map.remove(to).is_some(),
+    }
+}
+
+// keeps track of how many emotes each user sent recently
+#[derive(Clone)]
+pub struct EmoteRateLimiter {
+    // the times at which each user sent emotes, oldest first. Kept by session so that users can't
+    // reset their limit by changing their name or tags.
+    uses: HashMap<u32, VecDeque<Instant>>,
+}
+
+impl EmoteRateLimiter {
+    pub fn new() -> Self {
+        Self {
+            uses: HashMap::new(),
+        }
+    }
+
+    // records that the user wants to send a number of emotes. Returns the number of seconds until
+    // they are allowed to if it would exceed the rate limit.
+    fn check(&mut self, session: u32, count: usize, config: &EmoteConfig) -> Result<(), u64> {
+        if config.rate_limit_emotes == 0 {
+            return Ok(());
+        }
+        let window = Duration::from_secs(config.rate_limit_seconds);
+        let now = Instant::now();
+        // users that haven't sent emotes within the window are forgotten
+        self.uses.retain(|_, uses| uses.back().map_or(false, |time| now.duration_since(*time) < window));
+        let uses = self.uses.entry(session).or_default();
+        while uses.front().map_or(false, |time| now.duration_since(*time) >= window) {
+            uses.pop_front();
+        }
+        if uses.len() + count > config.rate_limit_emotes {
+            let wait = uses.front().map_or(window, |time| window - now.duration_since(*time));
+            return Err(wait.as_secs() + 1);
+        }
+        uses.extend(std::iter::repeat(now).take(count));
+        Ok(())
+    }
+}
+
+// sessions are reused by the server, so the next user with the same session must not inherit the
+// rate limit of someone who disconnected
+pub fn user_disconnected(t: Arc<Mutex<Data>>, _c: V1Client, e: Server_Event) -> FutureValue<bool> {
+    future(async move {
+        if e.has_user() {
+            t.lock().unwrap().volatile_data.emote_rate_limiter.uses.remove(&e.get_user().get_session());
+        }
+        true
+    })
+}
+
+fn rate_limit_notice(wait: u64) -> String {
+    format!("You are sending emotes too quickly, they will be shown again in {} seconds", wait)
+}
+
+// the users that a message will be delivered to, except for its sender
+async fn recipients(c: &V1Client, filter: &TextMessage_Filter) -> Option<Vec<User>> {
+    let message = filter.get_message();
+    let mut query = User_Query::new();
+    query.set_server(filter.get_server().to_owned());
+    let users = c.user_query_async(&query).ok()?.await.ok()?.take_users().into_vec();
+
+    let mut channel_ids: Vec<u32> = message.get_channels().iter().map(|channel| channel.get_id()).collect();
+    if !message.get_trees().is_empty() {
+        let mut query = Channel_Query::new();
+        query.set_server(filter.get_server().to_owned());
+        let channels = c.channel_query_async(&query).ok()?.await.ok()?.take_channels().into_vec();
+        let mut trees: Vec<u32> = message.get_trees().iter().map(|channel| channel.get_id()).collect();
+        while let Some(id) = trees.pop() {
+            channel_ids.push(id);
+            trees.extend(channels.iter()
+                .filter(|channel| channel.has_parent() && channel.get_parent().get_id() == id)
+                .map(|channel| channel.get_id()));
+        }
+    }
+
+    let sessions: Vec<u32> = message.get_users().iter().map(|user| user.get_session()).collect();
+    let sender = message.get_actor().get_session();
+    Some(users.into_iter()
+        .filter(|user| user.get_session() != sender)
+        .filter(|user| sessions.contains(&user.get_session()) || channel_ids.contains(&user.get_channel().get_id()))
+        .collect())
+}
+
+const PREFERENCES_FILE: &'static str = "emote_preferences.toml";
+// image emotes can't be made smaller or larger than this
+const MIN_EMOTE_HEIGHT: u32 = 8;
+const MAX_EMOTE_HEIGHT: u32 = 128;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct EmotePreferences {
+    users: HashMap<String, EmotePreference>,
+}
+
+impl Persistent for EmotePreferences {}
+
+impl EmotePreferences {
+    fn variant(&self, user: &str, config: &EmoteConfig) -> Variant {
+        self.users.get(user).map_or(Variant::Images(config.emote_height), |p| p.variant(config))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct EmotePreference {
+    // show the names of image emotes instead of the images
+    disabled: bool,
+    // overrides the configured emote height
+    height: Option<u32>,
+}
+
+impl EmotePreference {
+    fn variant(&self, config: &EmoteConfig) -> Variant {
+        if self.disabled {
+            Variant::TextOnly
+        } else {
+            Variant::Images(self.height.unwrap_or(config.emote_height))
+        }
+    }
+}
+
+// changes the preferences of the user and returns the reply to them
+fn set_preference(server_path: &PathBuf, user: &str, args: &[&str]) -> String {
+    let path = server_path.join(PREFERENCES_FILE);
+    let mut preferences = EmotePreferences::load(&path);
+    let preference = preferences.users.entry(user.to_owned()).or_default();
+    let reply = match args {
+        ["off"] => {
+            preference.disabled = true;
+            "Image emotes will be shown as text".to_string()
+        },
+        ["on"] => {
+            preference.disabled = false;
+            "Image emotes will be shown as images".to_string()
+        },
+        ["size", "default"] => {
+            preference.height = None;
+            "Image emotes will be shown with the default size".to_string()
+        },
+        ["size", height] => match height.parse::<u32>() {
+            Ok(height) if height >= MIN_EMOTE_HEIGHT && height <= MAX_EMOTE_HEIGHT => {
+                preference.height = Some(height);
+                format!("Image emotes will be shown {} pixels high", height)
+            },
+            _ => return error_html(format!("The size has to be a number of pixels between {} and {}", MIN_EMOTE_HEIGHT, MAX_EMOTE_HEIGHT)),
+        },
+        _ => return error_html("Unknown preference"),
+    };
+    if !preference.disabled && preference.height.is_none() {
+        // don't keep users with default preferences around
+        preferences.users.remove(user);
+    }
+    if let Err(e) = preferences.store(&path) {
+        eprintln!("Failed to store emote preferences: {}", e);
+    }
+    reply
+}
+
+const REACT_COMMAND: &'static str = "!react";
+// number of characters of a message that are quoted in reactions to it
+const REACTION_PREVIEW_LENGTH: usize = 30;
+
+// returns the emote of a reaction, which is either sent as `!react <emote>` or as a message that
+// only consists of `+:emote:`
+fn reaction_emote(text: &str, delimiter: char) -> Option<&str> {
+    let text = text.trim();
+    let mut words = text.splitn(2, char::is_whitespace);
+    if words.next() == Some(REACT_COMMAND) {
+        let emote = words.next()?.trim();
+        let emote = emote.strip_prefix(delimiter).unwrap_or(emote);
+        let emote = emote.strip_suffix(delimiter).unwrap_or(emote);
+        return Some(emote).filter(|emote| !emote.is_empty());
+    }
+    text.strip_prefix('+')?
+        .strip_prefix(delimiter)?
+        .strip_suffix(delimiter)
+        .filter(|emote| !emote.is_empty() && !emote.contains(delimiter))
+}
+
+// keeps track of the most recent message in each channel and the reactions to it
+#[derive(Clone)]
+pub struct ReactionTracker {
+    last_messages: HashMap<u32, ReactedMessage>,
+}
+
+#[derive(Clone)]
+struct ReactedMessage {
+    sender: String,
+    preview: String,
+    // emote name, rendered emote and the users that reacted with it, in the order they were first used
+    reactions: Vec<(String, String, Vec<String>)>,
+}
+
+impl ReactionTracker {
+    pub fn new() -> Self {
+        Self {
+            last_messages: HashMap::new(),
+        }
+    }
+
+    fn message_sent(&mut self, channel_id: u32, sender: &str, text: &str) {
+        let plain_text = plain_text(text);
+        let mut preview: String = plain_text.chars().take(REACTION_PREVIEW_LENGTH).collect();
+        if preview.len() < plain_text.len() {
+            preview.push('…');
+        }
+        self.last_messages.insert(channel_id, ReactedMessage {
+            sender: sender.to_owned(),
+            preview: preview,
+            reactions: vec![],
+        });
+    }
+
+    // adds a reaction to the most recent message in the channel and returns the line announcing
+    // all reactions to it, or None if there is no message to react to
+    fn react(&mut self, channel_id: u32, user: &str, name: &str, html: String) -> Option<String> {
+        let message = self.last_messages.get_mut(&channel_id)?;
+        if let Some(reaction) = message.reactions.iter_mut().find(|reaction| reaction.0 == name) {
+            if !reaction.2.iter().any(|u| u == user) {
+                reaction.2.push(user.to_owned());
+            }
+        } else {
+            message.reactions.push((name.to_owned(), html, vec![user.to_owned()]));
+        }
+        let target = format!("<b>{}</b>'s message \"<i>{}</i>\"", escape_html(&message.sender), message.preview);
+        if let [(_, html, users)] = message.reactions.as_slice() {
+            if users.len() == 1 {
+                return Some(format!("<b>{}</b> reacted {} to {}", escape_html(user), html, target));
+            }
+        }
+        let reactions: Vec<String> = message.reactions.iter().map(|(_, html, users)| {
+            let users: Vec<String> = users.iter().map(|user| escape_html(user)).collect();
+            format!("{} ×{} ({})", html, users.len(), users.join(", "))
+        }).collect();
+        Some(format!("Reactions to {}: {}", target, reactions.join(" · ")))
+    }
+}
+
+// the text of an html message without any tags
+fn plain_text(text: &str) -> String {
+    html_segments(text).into_iter()
+        .filter(|(segment, _)| !segment.starts_with('<'))
+        .map(|(segment, _)| segment)
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+const COMMAND: &'static str = "!emotes";
+const STATS_FILE: &'static str = "emote_stats.toml";
+// number of entries shown by `!emotes top`
+const LEADERBOARD_SIZE: usize = 10;
+
+// commands that can only be used by users with write permission on the root channel
+const ADMIN_COMMANDS: [&'static str; 5] = ["import", "export", "rename", "ban", "unban"];
+
+fn emote_command(server_path: &PathBuf, settings: &EmoteSettings, user: &str, is_admin: bool, args: &[&str]) -> String {
+    if !args.is_empty() && ADMIN_COMMANDS.contains(&args[0]) && !is_admin {
+        return error_html("Only admins can use this command");
+    }
+    let stats = EmoteStats::load(server_path.join(STATS_FILE));
+    match args {
+        ["import", archive] => import_pack(server_path, settings, archive).unwrap_or_else(error_html),
+        ["export"] => export_pack(server_path, settings, "zip").unwrap_or_else(error_html),
+        ["export", format] => export_pack(server_path, settings, format).unwrap_or_else(error_html),
+        ["rename", old_name, new_name] => rename_emote(server_path, settings, old_name, new_name).unwrap_or_else(error_html),
+        ["search", term] => search_emotes(server_path, settings, term),
+        ["ban", name] => ban_emote(server_path, name, true),
+        ["unban", name] => ban_emote(server_path, name, false),
+        ["on"] | ["off"] | ["size", _] => set_preference(server_path, user, args),
+        ["top"] => leaderboard("😀 Most used emotes:", &stats.emotes),
+        ["top", user] => if let Some(counts) = stats.users.get(base_name(user)) {
+            leaderboard(&format!("😀 Emotes used most by {}:", escape_html(user)), counts)
+        } else {
+            format!("<b>{}</b> hasn't used any emotes yet", escape_html(user))
+        },
+        ["unused"] => {
+            let unused: Vec<String> = emote_names(&server_path.join("emotes"), settings).into_iter()
+                .filter(|name| !stats.emotes.contains_key(name))
+                .collect();
+            if unused.is_empty() {
+                "Every emote has been used at least once".to_string()
+            } else {
+                format!("<h3>🗑️ Unused emotes:</h3>{}", unused.join("<br/>"))
+            }
+        },
+        _ => "<br/><h3>😀 !emotes commands:</h3><ul> \
+              <li><tt>!emotes top</tt><br/>(List the most used emotes.)</li> \
+              <li><tt>!emotes top <b>user</b></tt><br/>(List the emotes used most by <b>user</b>.)</li> \
+              <li><tt>!emotes unused</tt><br/>(List the emotes that have never been used.)</li> \
+              <li><tt>!emotes search <b>term</b></tt><br/>(List the emotes with a name, alias or tag that contains <b>term</b>.)</li> \
+              <li><tt>!emotes off</tt><br/>(Show image emotes as text. Typing <tt>!emotes on</tt> shows them as images again.)</li> \
+              <li><tt>!emotes size <b>pixels</b></tt><br/>(Change the height of image emotes. Typing <tt>!emotes size default</tt> resets it.)</li> \
+              <li><tt>!emotes import <b>archive</b></tt><br/>(Admins only. Import an emote pack from the <tt>emote_packs</tt> directory.)</li> \
+              <li><tt>!emotes export <b>zip|tar</b></tt><br/>(Admins only. Export all emotes to the <tt>emote_packs</tt> directory.)</li> \
+              <li><tt>!emotes rename <b>old</b> <b>new</b></tt><br/>(Admins only. Rename an emote. The old name keeps working.)</li> \
+              <li><tt>!emotes ban <b>name</b></tt><br/>(Admins only. Stop showing an emote. Typing <tt>!emotes unban <b>name</b></tt> allows it again.)</li></ul>".to_string()
+    }
+}
+
+// directory in the server's data directory that emote packs are imported from and exported to
+const PACK_DIR: &'static str = "emote_packs";
+// name of the manifest in emote packs and in the emote directory
+const MANIFEST_FILE: &'static str = "manifest.toml";
+// files in emote packs that are larger than this are skipped
+const MAX_PACK_FILE_BYTES: u64 = 1024 * 1024;
+
+// number of redirects that are followed when looking up a deprecated emote name
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Manifest {
+    emotes: Vec<ManifestEntry>,
+    // names of emotes that were renamed, mapped to their new names
+    redirects: HashMap<String, String>,
+    // emotes that are not substituted
+    banned: Vec<String>,
+}
+
+impl Persistent for Manifest {}
+
+impl Manifest {
+    // all names that refer to another emote
+    fn aliases(&self) -> HashMap<String, String> {
+        let mut aliases = self.redirects.clone();
+        for entry in self.emotes.iter() {
+            for alias in entry.aliases.iter() {
+                aliases.insert(alias.to_owned(), entry.name.to_owned());
+            }
+        }
+        aliases
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct ManifestEntry {
+    name: String,
+    // file in the emote pack, defaults to the name of the emote with a png or txt extension
+    file: Option<String>,
+    aliases: Vec<String>,
+    tags: Vec<String>,
+}
+
+// imports the images and text emotes in an archive from the pack directory and returns a report
+// of what was imported
+fn import_pack(server_path: &PathBuf, settings: &EmoteSettings, archive: &str) -> Result<String, String> {
+    let pack_dir = server_path.join(PACK_DIR);
+    drop(std::fs::create_dir_all(&pack_dir));
+    // only files directly inside of the pack directory can be imported
+    if Path::new(archive).file_name() != Some(OsStr::new(archive)) {
+        return Err(format!("<tt>{}</tt> is not a file name", escape_html(archive)));
+    }
+    let files = read_archive(&pack_dir.join(archive))?;
+    let dir = server_path.join("emotes");
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let mut catalogue = Manifest::load(&manifest_path);
+
+    let manifest = match files.get(MANIFEST_FILE) {
+        Some(manifest) => {
+            let manifest = String::from_utf8_lossy(manifest);
+            toml::from_str::<Manifest>(&manifest).map_err(|e| format!("Invalid manifest: {}", escape_html(&e.to_string())))?
+        },
+        None => Manifest::default(),
+    };
+    let entries = pack_entries(&files, manifest);
+
+    let known_names: Vec<String> = emote_names(&dir, settings).into_iter()
+        .chain(catalogue.aliases().into_iter().map(|(alias, _)| alias))
+        .collect();
+    let mut imported = vec![];
+    let mut problems = vec![];
+    for mut entry in entries {
+        let name = entry.name.clone();
+        if !settings.regex.is_match(&name) {
+            problems.push(format!("<b>{}</b>: invalid name", escape_html(&name)));
+            continue;
+        }
+        if known_names.contains(&name) || imported.contains(&name) {
+            problems.push(format!("<b>{}</b>: an emote with this name already exists", name));
+            continue;
         }
-        (true, filter)
+        entry.aliases.retain(|alias| {
+            let valid = settings.regex.is_match(alias) && !known_names.contains(alias);
+            if !valid {
+                problems.push(format!("<b>{}</b>: alias <b>{}</b> is invalid or already exists", name, escape_html(alias)));
+            }
+            valid
+        });
+        let file = entry.file.clone().or_else(|| {
+            [format!("{}.png", name), format!("{}.txt", name)].iter().find(|file| files.contains_key(*file)).cloned()
+        });
+        let contents = if let Some(contents) = file.as_ref().and_then(|file| files.get(file)) {
+            contents
+        } else {
+            problems.push(format!("<b>{}</b>: file not found in the archive", name));
+            continue;
+        };
+        let result = if file.as_ref().map_or(false, |file| file.ends_with(".txt")) {
+            std::fs::write(dir.join(format!("{}.txt", name)), contents).map_err(|e| e.to_string())
+        } else {
+            save_image(contents, &dir.join(format!("{}.png", name)), settings.config.import_height)
+        };
+        if let Err(e) = result {
+            problems.push(format!("<b>{}</b>: {}", name, escape_html(&e)));
+            continue;
+        }
+        entry.file = None;
+        if !entry.aliases.is_empty() || !entry.tags.is_empty() {
+            catalogue.emotes.retain(|e| e.name != name);
+            catalogue.emotes.push(entry);
+        }
+        imported.push(name);
+    }
+    catalogue.store(&manifest_path).map_err(|e| e.to_string())?;
+
+    let mut report = format!("<h3>📦 Imported {} emotes</h3>{}", imported.len(), imported.join(", "));
+    if !problems.is_empty() {
+        report.push_str(&format!("<h3>⚠️ Skipped:</h3>{}", problems.join("<br/>")));
+    }
+    Ok(report)
+}
+
+// the emotes in a pack: those listed in its manifest, followed by every other image or text file
+// in it, which is imported under its file name
+fn pack_entries(files: &HashMap<String, Vec<u8>>, manifest: Manifest) -> Vec<ManifestEntry> {
+    let listed_files: Vec<String> = manifest.emotes.iter()
+        .flat_map(|entry| match entry.file.as_ref() {
+            Some(file) => vec![file.to_owned()],
+            None => vec![format!("{}.png", entry.name), format!("{}.txt", entry.name)],
+        })
+        .collect();
+    let mut names: Vec<&String> = files.keys().filter(|file| !listed_files.contains(file)).collect();
+    names.sort();
+    let unlisted = names.into_iter().filter_map(|file| {
+        let path = Path::new(file);
+        match path.extension()?.to_str()? {
+            "png" | "gif" | "jpg" | "jpeg" | "webp" | "txt" => Some(ManifestEntry {
+                name: path.file_stem()?.to_str()?.to_owned(),
+                file: Some(file.to_owned()),
+                ..ManifestEntry::default()
+            }),
+            _ => None,
+        }
+    });
+    manifest.emotes.into_iter().chain(unlisted).collect()
+}
+
+// reads all files in a zip or (gzipped) tar archive, keyed by their file names
+fn read_archive(path: &PathBuf) -> Result<HashMap<String, Vec<u8>>, String> {
+    let file = std::fs::File::open(path).map_err(|_| format!("Couldn't open <tt>{}</tt>", path.display()))?;
+    let name = path.to_string_lossy().to_lowercase();
+    let mut files = HashMap::new();
+    let mut add_file = |file_path: &Path, reader: &mut dyn Read, size: u64| {
+        if let Some(file_name) = file_path.file_name().and_then(|name| name.to_str()) {
+            let mut contents = vec![];
+            if size <= MAX_PACK_FILE_BYTES && reader.read_to_end(&mut contents).is_ok() {
+                files.insert(file_name.to_owned(), contents);
+            }
+        }
+    };
+    if name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|e| e.to_string())?;
+            if !entry.is_dir() {
+                let size = entry.size();
+                add_file(&PathBuf::from(entry.name()), &mut entry, size);
+            }
+        }
+    } else if name.ends_with(".tar") || name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        let reader: Box<dyn Read> = if name.ends_with(".tar") {
+            Box::new(file)
+        } else {
+            Box::new(flate2::read::GzDecoder::new(file))
+        };
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            if entry.header().entry_type().is_file() {
+                let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+                let size = entry.size();
+                add_file(&path, &mut entry, size);
+            }
+        }
+    } else {
+        return Err("Emote packs have to be .zip, .tar, .tar.gz or .tgz archives".to_string());
+    }
+    Ok(files)
+}
+
+// converts an image to png, shrinking it to the given height if it is larger
+fn save_image(contents: &[u8], path: &PathBuf, max_height: u32) -> Result<(), String> {
+    let mut image = image::load_from_memory(contents).map_err(|e| e.to_string())?;
+    if image.height() > max_height {
+        image = image.resize(u32::MAX, max_height, image::imageops::FilterType::Lanczos3);
+    }
+    image.save_with_format(path, image::ImageFormat::Png).map_err(|e| e.to_string())
+}
+
+// writes all emotes and the manifest to an archive in the pack directory
+fn export_pack(server_path: &PathBuf, settings: &EmoteSettings, format: &str) -> Result<String, String> {
+    let dir = server_path.join("emotes");
+    let pack_dir = server_path.join(PACK_DIR);
+    std::fs::create_dir_all(&pack_dir).map_err(|e| e.to_string())?;
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let archive_name = format!("emotes-{}.{}", timestamp, format);
+    let archive_path = pack_dir.join(&archive_name);
+
+    let mut files: Vec<PathBuf> = emote_names(&dir, settings).into_iter()
+        .flat_map(|name| vec![dir.join(format!("{}.png", name)), dir.join(format!("{}.txt", name))])
+        .filter(|path| path.exists())
+        .collect();
+    if dir.join(MANIFEST_FILE).exists() {
+        files.push(dir.join(MANIFEST_FILE));
+    }
+
+    match format {
+        "zip" => {
+            let file = std::fs::File::create(&archive_path).map_err(|e| e.to_string())?;
+            let mut archive = zip::ZipWriter::new(file);
+            for path in files.iter() {
+                let name = path.file_name().unwrap().to_string_lossy();
+                let contents = std::fs::read(path).map_err(|e| e.to_string())?;
+                archive.start_file(name, zip::write::FileOptions::default()).map_err(|e| e.to_string())?;
+                archive.write_all(&contents).map_err(|e| e.to_string())?;
+            }
+            archive.finish().map_err(|e| e.to_string())?;
+        },
+        "tar" => {
+            let file = std::fs::File::create(&archive_path).map_err(|e| e.to_string())?;
+            let mut archive = tar::Builder::new(file);
+            for path in files.iter() {
+                archive.append_path_with_name(path, path.file_name().unwrap()).map_err(|e| e.to_string())?;
+            }
+            archive.finish().map_err(|e| e.to_string())?;
+        },
+        _ => return Err("Emote packs can be exported as <tt>zip</tt> or <tt>tar</tt>".to_string()),
+    }
+    Ok(format!("Exported {} files to <tt>{}/{}</tt>", files.len(), PACK_DIR, archive_name))
+}
+
+// lists the emotes whose name, aliases or tags contain the search term
+fn search_emotes(server_path: &PathBuf, settings: &EmoteSettings, term: &str) -> String {
+    let dir = server_path.join("emotes");
+    let manifest = Manifest::load(dir.join(MANIFEST_FILE));
+    let term = term.to_lowercase();
+    let results: Vec<String> = emote_names(&dir, settings).into_iter().filter_map(|name| {
+        let entry = manifest.emotes.iter().find(|entry| entry.name == name);
+        let aliases = entry.map_or(vec![], |entry| entry.aliases.clone());
+        let tags = entry.map_or(vec![], |entry| entry.tags.clone());
+        if !name.contains(&term) && !aliases.iter().chain(tags.iter()).any(|word| word.to_lowercase().contains(&term)) {
+            return None;
+        }
+        let mut result = format!("<b>{}</b>", name);
+        if !aliases.is_empty() {
+            result.push_str(&format!(" (also {})", escape_html(&aliases.join(", "))));
+        }
+        if !tags.is_empty() {
+            result.push_str(&format!(" <i>{}</i>", escape_html(&tags.join(", "))));
+        }
+        Some(result)
+    }).collect();
+    if results.is_empty() {
+        format!("No emotes found for <b>{}</b>", escape_html(&term))
+    } else {
+        format!("<h3>🔍 Emotes matching {}:</h3>{}", escape_html(&term), results.join("<br/>"))
+    }
+}
+
+// renames the files of an emote and keeps the old name working as a deprecated name
+fn rename_emote(server_path: &PathBuf, settings: &EmoteSettings, old_name: &str, new_name: &str) -> Result<String, String> {
+    let dir = server_path.join("emotes");
+    let names = emote_names(&dir, settings);
+    if !names.iter().any(|name| name == old_name) {
+        return Err(format!("There is no emote named <b>{}</b>", escape_html(old_name)));
+    }
+    let manifest_path = dir.join(MANIFEST_FILE);
+    let mut manifest = Manifest::load(&manifest_path);
+    if !settings.regex.is_match(new_name) || names.iter().any(|name| name == new_name) || manifest.aliases().contains_key(new_name) {
+        return Err(format!("<b>{}</b> is invalid or already taken", escape_html(new_name)));
+    }
+    for extension in ["png", "txt"].iter() {
+        let old_path = dir.join(format!("{}.{}", old_name, extension));
+        if old_path.exists() {
+            std::fs::rename(&old_path, dir.join(format!("{}.{}", new_name, extension))).map_err(|e| e.to_string())?;
+        }
+    }
+    for entry in manifest.emotes.iter_mut().filter(|entry| entry.name == old_name) {
+        entry.name = new_name.to_owned();
+    }
+    for target in manifest.redirects.values_mut().filter(|target| *target == old_name) {
+        *target = new_name.to_owned();
+    }
+    manifest.redirects.insert(old_name.to_owned(), new_name.to_owned());
+    manifest.store(&manifest_path).map_err(|e| e.to_string())?;
+
+    // keep the usage statistics of the emote
+    let stats_path = server_path.join(STATS_FILE);
+    let mut stats = EmoteStats::load(&stats_path);
+    if let Some(count) = stats.emotes.remove(old_name) {
+        stats.emotes.insert(new_name.to_owned(), count);
+        for counts in stats.users.values_mut() {
+            if let Some(count) = counts.remove(old_name) {
+                counts.insert(new_name.to_owned(), count);
+            }
+        }
+        drop(stats.store(&stats_path));
+    }
+    Ok(format!("Renamed <b>{}</b> to <b>{}</b>", old_name, new_name))
+}
+
+fn ban_emote(server_path: &PathBuf, name: &str, banned: bool) -> String {
+    let manifest_path = server_path.join("emotes").join(MANIFEST_FILE);
+    let mut manifest = Manifest::load(&manifest_path);
+    // bans apply to the emote that an alias or deprecated name refers to
+    let mut name = name.to_owned();
+    let aliases = manifest.aliases();
+    for _ in 0..MAX_REDIRECTS {
+        match aliases.get(&name) {
+            Some(target) => name = target.to_owned(),
+            None => break,
+        }
+    }
+    manifest.banned.retain(|n| n != &name);
+    if banned {
+        manifest.banned.push(name.clone());
+    }
+    if let Err(e) = manifest.store(&manifest_path) {
+        return error_html(e.to_string());
+    }
+    format!("<b>{}</b> is {}", escape_html(&name), if banned { "banned" } else { "no longer banned" })
+}
+
+fn leaderboard(title: &str, counts: &HashMap<String, u64>) -> String {
+    let mut counts: Vec<(&String, &u64)> = counts.iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    counts.into_iter().take(LEADERBOARD_SIZE).enumerate().fold(format!("<h3>{}</h3>", title), |mut list, (index, (name, count))| {
+        list.push_str(&format!("[{}] <b>{}</b>: {}<br/>", index + 1, name, count));
+        list
     })
 }
+
+// names of all emotes in the emote directory, sorted alphabetically
+fn emote_names(dir: &PathBuf, settings: &EmoteSettings) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir).into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "png" || ext == "txt"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_owned()))
+        .filter(|name| settings.regex.is_match(name))
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+// the statistics are only touched while holding the data lock, since several messages can be
+// filtered at the same time and would otherwise overwrite each other's counts
+fn record_usage(t: &Arc<Mutex<Data>>, user: &str, used_emotes: &[String]) {
+    if used_emotes.is_empty() {
+        return;
+    }
+    let data = t.lock().unwrap();
+    let path = data.path.join(STATS_FILE);
+    let mut stats = EmoteStats::load(&path);
+    for name in used_emotes {
+        *stats.emotes.entry(name.to_owned()).or_insert(0) += 1;
+        *stats.users.entry(user.to_owned()).or_default().entry(name.to_owned()).or_insert(0) += 1;
+    }
+    if let Err(e) = stats.store(&path) {
+        eprintln!("Failed to store emote statistics: {}", e);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct EmoteStats {
+    // number of times each emote was used
+    emotes: HashMap<String, u64>,
+    // number of times each emote was used by each user
+    users: HashMap<String, HashMap<String, u64>>,
+}
+
+impl Persistent for EmoteStats {}
+
+async fn send_reply(c: &V1Client, filter: &TextMessage_Filter, text: String) {
+    let mut message = TextMessage::new();
+    message.set_server(filter.get_server().to_owned());
+    message.set_users(RepeatedField::from_vec(vec![filter.get_message().get_actor().to_owned()]));
+    message.set_text(text);
+    if let Ok(u) = c.text_message_send_async(&message) { drop(u.await); }
+}
+
+// name of the channel a message was sent to, or the channel of its sender for private messages
+async fn channel_name(c: &V1Client, filter: &TextMessage_Filter) -> String {
+    let message = filter.get_message();
+    let channel = if let Some(channel) = message.get_channels().first() {
+        channel.to_owned()
+    } else {
+        message.get_actor().get_channel().to_owned()
+    };
+    if channel.has_name() || !channel.has_id() {
+        return channel.get_name().to_owned();
+    }
+    let mut query = channel;
+    query.set_server(filter.get_server().to_owned());
+    if let Ok(u) = c.channel_get_async(&query) {
+        if let Ok(channel) = u.await {
+            return channel.get_name().to_owned();
+        }
+    }
+    String::new()
+}
+
+fn unescape_html(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+// built in emoji shortcodes, sorted by name so they can be binary searched
+const SHORTCODES: &'static [(&'static str, &'static str)] = &[
+    ("+1", "👍"), ("-1", "👎"), ("100", "💯"), ("alien", "👽"), ("angry", "😠"), ("anguished", "😧"),
+    ("apple", "🍎"), ("astonished", "😲"), ("baby", "👶"), ("balloon", "🎈"), ("banana", "🍌"),
+    ("basketball", "🏀"), ("bear", "🐻"), ("bee", "🐝"), ("beer", "🍺"), ("beers", "🍻"), ("bell", "🔔"),
+    ("bird", "🐦"), ("birthday", "🎂"), ("blush", "😊"), ("bomb", "💣"), ("boom", "💥"), ("brain", "🧠"),
+    ("broken_heart", "💔"), ("bug", "🐛"), ("butterfly", "🦋"), ("cactus", "🌵"), ("cake", "🍰"),
+    ("cat", "🐱"), ("check", "✔️"), ("checkered_flag", "🏁"), ("chicken", "🐔"),
+    ("christmas_tree", "🎄"), ("clap", "👏"), ("cloud", "☁️"), ("clown_face", "🤡"),
+    ("cocktail", "🍸"), ("coffee", "☕"), ("cold_face", "🥶"), ("cold_sweat", "😰"),
+    ("confetti_ball", "🎊"), ("confounded", "😖"), ("confused", "😕"), ("cookie", "🍪"), ("cool", "🆒"),
+    ("cop", "👮"), ("cow", "🐮"), ("cowboy_hat_face", "🤠"), ("crossed_fingers", "🤞"), ("crown", "👑"),
+    ("cry", "😢"), ("crying_cat_face", "😿"), ("dancer", "💃"), ("dash", "💨"), ("disappointed", "😞"),
+    ("dizzy", "💫"), ("dizzy_face", "😵"), ("dog", "🐶"), ("earth_americas", "🌎"),
+    ("evergreen_tree", "🌲"), ("exclamation", "❗"), ("exploding_head", "🤯"),
+    ("expressionless", "😑"), ("eyes", "👀"), ("facepalm", "🤦"), ("fearful", "😨"), ("fire", "🔥"),
+    ("fish", "🐟"), ("fist", "✊"), ("flushed", "😳"), ("fox_face", "🦊"), ("frog", "🐸"),
+    ("frowning", "😦"), ("game_die", "🎲"), ("gem", "💎"), ("ghost", "👻"), ("gift", "🎁"),
+    ("grimacing", "😬"), ("grin", "😁"), ("grinning", "😀"), ("hamburger", "🍔"), ("hammer", "🔨"),
+    ("hand", "✋"), ("hand_over_mouth", "🤭"), ("handshake", "🤝"), ("headphones", "🎧"),
+    ("hear_no_evil", "🙉"), ("heart", "❤️"), ("heart_eyes", "😍"), ("hearts", "♥️"),
+    ("heavy_check_mark", "✔️"), ("horse", "🐴"), ("hot_face", "🥵"), ("hourglass", "⌛"),
+    ("hugs", "🤗"), ("hushed", "😯"), ("innocent", "😇"), ("joy", "😂"), ("kiss", "😘"),
+    ("kissing", "😗"), ("koala", "🐨"), ("laughing", "😆"), ("lion", "🦁"), ("lock", "🔒"),
+    ("lying_face", "🤥"), ("mag", "🔍"), ("mask", "😷"), ("medal", "🏅"), ("microphone", "🎤"),
+    ("money_mouth_face", "🤑"), ("money_with_wings", "💸"), ("moneybag", "💰"), ("monkey", "🐒"),
+    ("monocle_face", "🧐"), ("moon", "🌙"), ("mouse", "🐭"), ("muscle", "💪"), ("mute", "🔇"),
+    ("nail_care", "💅"), ("nauseated_face", "🤢"), ("nerd_face", "🤓"), ("neutral_face", "😐"),
+    ("no_entry", "⛔"), ("no_mouth", "😶"), ("octopus", "🐙"), ("ok", "🆗"), ("ok_hand", "👌"),
+    ("open_mouth", "😮"), ("panda_face", "🐼"), ("party", "🥳"), ("partying_face", "🥳"),
+    ("penguin", "🐧"), ("pensive", "😔"), ("persevere", "😣"), ("pig", "🐷"), ("pizza", "🍕"),
+    ("pleading_face", "🥺"), ("point_down", "👇"), ("point_left", "👈"), ("point_right", "👉"),
+    ("point_up", "☝️"), ("poop", "💩"), ("popcorn", "🍿"), ("pray", "🙏"), ("pumpkin", "🎃"),
+    ("question", "❓"), ("rabbit", "🐰"), ("rage", "😡"), ("rainbow", "🌈"), ("raised_eyebrow", "🤨"),
+    ("raised_hands", "🙌"), ("relaxed", "☺️"), ("relieved", "😌"), ("robot", "🤖"), ("rocket", "🚀"),
+    ("rofl", "🤣"), ("rolling_eyes", "🙄"), ("rose", "🌹"), ("santa", "🎅"), ("satisfied", "😆"),
+    ("scream", "😱"), ("see_no_evil", "🙈"), ("shrug", "🤷"), ("shushing_face", "🤫"), ("skull", "💀"),
+    ("sleeping", "😴"), ("sleepy", "😪"), ("slightly_frowning_face", "🙁"),
+    ("slightly_smiling_face", "🙂"), ("smile", "😄"), ("smiley", "😃"),
+    ("smiling_face_with_three_hearts", "🥰"), ("smirk", "😏"), ("snake", "🐍"),
+    ("sneezing_face", "🤧"), ("snowflake", "❄️"), ("snowman", "⛄"), ("sob", "😭"), ("soccer", "⚽"),
+    ("sparkles", "✨"), ("speak_no_evil", "🙊"), ("star", "⭐"), ("star_struck", "🤩"),
+    ("stuck_out_tongue", "😛"), ("stuck_out_tongue_closed_eyes", "😝"),
+    ("stuck_out_tongue_winking_eye", "😜"), ("sun", "☀️"), ("sunflower", "🌻"), ("sunglasses", "😎"),
+    ("sweat", "😓"), ("sweat_drops", "💦"), ("sweat_smile", "😅"), ("taco", "🌮"), ("tada", "🎉"),
+    ("tea", "🍵"), ("thinking", "🤔"), ("thumbsdown", "👎"), ("thumbsup", "👍"), ("tiger", "🐯"),
+    ("tired_face", "😫"), ("tongue", "👅"), ("triumph", "😤"), ("trophy", "🏆"), ("tulip", "🌷"),
+    ("turtle", "🐢"), ("umbrella", "☂️"), ("unamused", "😒"), ("unicorn", "🦄"),
+    ("upside_down_face", "🙃"), ("v", "✌️"), ("video_game", "🎮"), ("vomiting_face", "🤮"),
+    ("warning", "⚠️"), ("wave", "👋"), ("weary", "😩"), ("whale", "🐳"), ("white_check_mark", "✅"),
+    ("wine_glass", "🍷"), ("wink", "😉"), ("worried", "😟"), ("writing_hand", "✍️"), ("x", "❌"),
+    ("yawning_face", "🥱"), ("yum", "😋"), ("zany_face", "🤪"), ("zap", "⚡"),
+    ("zipper_mouth_face", "🤐"), ("zzz", "💤"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // tokenizes with a resolver that only knows a few emotes and writes every emote as `<name>`
+    // and every piece of text as `[text]`
+    fn tokens(text: &str) -> String {
+        tokenize(text, ':', |name| if ["smile", "a", "b", "笑"].contains(&name) { Some(()) } else { None })
+            .into_iter()
+            .map(|token| match token {
+                Token::Text(text) => format!("[{}]", text),
+                Token::Emote(name, _) => format!("<{}>", name),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(tokens(""), "");
+        assert_eq!(tokens("hello"), "[hello]");
+        assert_eq!(tokens(":smile:"), "<smile>");
+    }
+
+    #[test]
+    fn multilingual_text() {
+        assert_eq!(tokens("héllo :smile: wörld"), "[héllo ]<smile>[ wörld]");
+        assert_eq!(tokens("ça va:smile:"), "[ça va]<smile>");
+        assert_eq!(tokens("🎉:smile:🎉"), "[🎉]<smile>[🎉]");
+        assert_eq!(tokens("👨‍👩‍👧 :smile: 👍🏽"), "[👨‍👩‍👧 ]<smile>[ 👍🏽]");
+        assert_eq!(tokens("日本語:smile:中文"), "[日本語]<smile>[中文]");
+        assert_eq!(tokens("こんにちは :笑: привет"), "[こんにちは ]<笑>[ привет]");
+        assert_eq!(tokens("مرحبا :smile:"), "[مرحبا ]<smile>");
+    }
+
+    #[test]
+    fn unknown_emotes_are_left_alone() {
+        assert_eq!(tokens(":nope:"), "[:nope:]");
+        assert_eq!(tokens("日本:語:"), "[日本:語:]");
+    }
+
+    #[test]
+    fn empty_and_adjacent_emotes() {
+        assert_eq!(tokens("::"), "[::]");
+        assert_eq!(tokens(":::"), "[:::]");
+        assert_eq!(tokens(":a::b:"), "<a><b>");
+        assert_eq!(tokens("::smile:"), "[:]<smile>");
+    }
+
+    #[test]
+    fn unterminated_delimiter() {
+        assert_eq!(tokens(":smile"), "[:smile]");
+        assert_eq!(tokens(":smile: :"), "<smile>[ :]");
+        assert_eq!(tokens("ends with:"), "[ends with:]");
+    }
+
+    #[test]
+    fn punctuation_around_emotes() {
+        assert_eq!(tokens("(:smile:)"), "[(]<smile>[)]");
+        assert_eq!(tokens(":smile:."), "<smile>[.]");
+        assert_eq!(tokens("hi,:smile:!"), "[hi,]<smile>[!]");
+    }
+
+    #[test]
+    fn closing_delimiter_can_open_an_emote() {
+        assert_eq!(tokens("10:30 :smile:"), "[10:30 ]<smile>");
+        assert_eq!(tokens("at 10:30:smile:"), "[at 10:30]<smile>");
+    }
+
+    #[test]
+    fn escaped_delimiter() {
+        assert_eq!(tokens("\\:smile:"), "[:smile:]");
+        assert_eq!(tokens("a \\:smile: b"), "[a ][:smile: b]");
+        assert_eq!(tokens("\\:smile: :smile:"), "[:smile: ]<smile>");
+        assert_eq!(tokens("日本\\:smile:"), "[日本][:smile:]");
+    }
+
+    fn template(template: &str, args: &[&str]) -> String {
+        render_template(template, args, &TemplateContext { sender: "alice <3".to_string(), channel: None })
+    }
+
+    #[test]
+    fn template_arguments_are_escaped() {
+        assert_eq!(template("{1}", &["<b>&"]), "&lt;b&gt;&amp;");
+        assert_eq!(template("{args}", &["a", "<i>"]), "a &lt;i&gt;");
+        assert_eq!(template("{sender}: {channel}", &[]), "alice &lt;3: ");
+    }
+
+    #[test]
+    fn escaped_template_arguments_are_not_escaped_again() {
+        assert_eq!(template("{1}", &["&lt;3 &amp; more"]), "&lt;3 &amp; more");
+        assert_eq!(template("{1}", &["&amp;lt;"]), "&amp;lt;");
+    }
+
+    #[test]
+    fn template_indices_out_of_range() {
+        assert_eq!(template("[{0}][{1}][{3}]", &["a", "b"]), "[][a][]");
+        assert_eq!(template("{2}", &[]), "");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept() {
+        assert_eq!(template("{foo} {} {-1}", &["a"]), "{foo} {} {-1}");
+        assert_eq!(template("{1} {1", &["a"]), "a {1");
+        assert_eq!(template("}{1}", &["a"]), "}a");
+    }
+
+    // an empty server data directory that is unique to a test
+    fn server_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("emotes-{}-{}", test, std::process::id()));
+        drop(std::fs::remove_dir_all(&path));
+        std::fs::create_dir_all(path.join("emotes")).unwrap();
+        path
+    }
+
+    #[test]
+    fn exported_packs_import_again() {
+        let settings = EmoteSettings::new();
+        let source = server_path("export");
+        let source_dir = source.join("emotes");
+        std::fs::write(source_dir.join("shrug.txt"), "¯\\_(ツ)_/¯").unwrap();
+        std::fs::write(source_dir.join("wave.txt"), "o/ {1}").unwrap();
+        image::RgbaImage::new(4, 4).save(source_dir.join("dot.png")).unwrap();
+        // only emotes with aliases or tags are in the manifest of the emote directory
+        let manifest = Manifest {
+            emotes: vec![ManifestEntry {
+                name: "wave".to_string(),
+                aliases: vec!["hi".to_string()],
+                ..ManifestEntry::default()
+            }],
+            ..Manifest::default()
+        };
+        manifest.store(source_dir.join(MANIFEST_FILE)).unwrap();
+
+        for format in ["zip", "tar"].iter() {
+            export_pack(&source, &settings, format).unwrap();
+            let archive = std::fs::read_dir(source.join(PACK_DIR)).unwrap()
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .find(|name| name.ends_with(format))
+                .unwrap();
+
+            let target = server_path(&format!("import-{}", format));
+            std::fs::create_dir_all(target.join(PACK_DIR)).unwrap();
+            std::fs::copy(source.join(PACK_DIR).join(&archive), target.join(PACK_DIR).join(&archive)).unwrap();
+            import_pack(&target, &settings, &archive).unwrap();
+
+            let target_dir = target.join("emotes");
+            assert_eq!(emote_names(&target_dir, &settings), vec!["dot", "shrug", "wave"]);
+            assert_eq!(std::fs::read_to_string(target_dir.join("shrug.txt")).unwrap(), "¯\\_(ツ)_/¯");
+            assert_eq!(std::fs::read_to_string(target_dir.join("wave.txt")).unwrap(), "o/ {1}");
+            assert!(image::open(target_dir.join("dot.png")).is_ok());
+            let imported = Manifest::load(target_dir.join(MANIFEST_FILE));
+            assert_eq!(imported.aliases().get("hi"), Some(&"wave".to_string()));
+            drop(std::fs::remove_dir_all(&target));
+        }
+        drop(std::fs::remove_dir_all(&source));
+    }
+}
@@ -1,7 +1,33 @@
-use crate::{FutureBool, Client, TextMessage, server::Event, DataMutex, future_from_async, future_from_bool,};
+use crate::{FutureBool, Client, TextMessage, Server, User, Channel, DatabaseUser, server::Event, DataMutex, future_from_async, future_from_bool,};
 use crate::database_user::Query;
+use crate::Persistent;
+use super::Data;
+use super::convenience::{error_html, escape_html, Visibility, PROFILES_FILE};
+use serde_derive::{Serialize, Deserialize};
+use chrono::{NaiveDateTime, Utc};
+use std::cmp::Ordering;
+use std::collections::HashMap;
 
-pub fn list_users<D>(_t: DataMutex<D>, mut c: Client, e: &Event) -> FutureBool {
+const KEYWORD: &'static str = "!users";
+const WHO_KEYWORD: &'static str = "!who";
+const PROFILE_KEYWORD: &'static str = "!profile";
+const MAX_BIO_LENGTH: usize = 500;
+// avatars that are larger than this are left out of profile cards so the message isn't rejected
+const MAX_AVATAR_BYTES: usize = 64 * 1024;
+// comments are html, so instead of cutting them off, longer ones are left out as well
+const MAX_COMMENT_BYTES: usize = 8 * 1024;
+const ROOT_CHANNEL: u32 = 0;
+const PROFILE_USAGE: &'static str = "<br/><h3>👤 !profile commands:</h3><ul> \
+                                     <li><tt>!profile <b>name</b></tt><br/>(Show the profile of the user with the given name.)</li> \
+                                     <li><tt>!profile set bio <b>text</b></tt><br/>(Change your bio. Typing <tt>!profile set bio</tt> without text removes it.)</li> \
+                                     <li><tt>!profile set contact on|off</tt><br/>(Show or hide your email address on your profile.)</li> \
+                                     <li><tt>!profile set visibility public|registered-only|hidden</tt><br/>(Choose who can find you with <tt>!users</tt> and <tt>!profile</tt>.)</li></ul>";
+const PAGE_SIZE: usize = 20;
+const USAGE: &'static str = "<tt>!users <b>[name]</b> [--sort name|active] [--online|--offline] [--page <b>number</b>] [--all]</tt>";
+// formats that murmur uses for the time a user was last active
+const LAST_ACTIVE_FORMATS: [&'static str; 2] = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S"];
+
+pub fn list_users(t: DataMutex<Data>, mut c: Client, e: &Event) -> FutureBool {
     let e = e.to_owned();
     if e.server.is_none() || e.user.is_none() || e.message.is_none() {
         return future_from_bool(true);
@@ -9,29 +35,588 @@ pub fn list_users<D>(_t: DataMutex<D>, mut c: Client, e: &Event) -> FutureBool {
     let user = e.user.unwrap();
     let server = e.server;
     let message = e.message.as_ref().unwrap().text().to_owned();
-    if message.trim() == "!users" {
-        return future_from_async(async move {
-            let query = Query {
-                server: server.clone(),
-                filter: None,
-            };
-            if let Ok(response) = c.database_user_query(query).await {
-                let user_list = response.into_inner().users.into_iter().fold("<b>Registered usernames:</b><br/>".to_string(), |mut list, user| {
-                    list.push_str(&format!("{}<br/>", user.name()));
-                    list
+    let mut words = message.split_whitespace();
+    if words.next() != Some(KEYWORD) {
+        return future_from_bool(true);
+    }
+    let options = match Options::parse(words) {
+        Ok(options) => options,
+        Err(error) => {
+            return future_from_async(async move {
+                send_reply(&mut c, server, user, format!("{}<br/>{}", error_html(&error), USAGE)).await;
+                false
+            });
+        }
+    };
+    future_from_async(async move {
+        let query = Query {
+            server: server.clone(),
+            filter: options.filter.as_ref().map(|filter| {
+                // murmur matches the filter with LIKE, so without wildcards only exact names would match
+                if filter.contains('%') { filter.to_owned() } else { format!("%{}%", filter) }
+            }),
+        };
+        let registered_users = match c.database_user_query(query).await {
+            Ok(response) => response.into_inner().users,
+            Err(_) => return false,
+        };
+        if options.all && !is_admin(&mut c, server.clone(), user.clone()).await {
+            send_reply(&mut c, server, user, error_html("Only admins can list every user")).await;
+            return false;
+        }
+        let server_path = t.lock_async().await.path.to_owned();
+        let profiles = Profiles::load(server_path.join(PROFILES_FILE));
+        let registered_users: Vec<DatabaseUser> = registered_users.into_iter()
+            .filter(|registered_user| options.all || profiles.is_visible_to(registered_user.id(), &user))
+            .collect();
+        let mut online_users = online_users(&mut c, server.clone()).await;
+        let channel_names = channel_names(&mut c, server.clone()).await;
+        let mut entries: Vec<Entry> = registered_users.into_iter()
+            .map(|registered_user| Entry {
+                online_user: online_users.remove(&registered_user.id()),
+                registered_user: registered_user,
+                last_active: None,
+            })
+            .filter(|entry| match options.status {
+                Status::Any => true,
+                Status::Online => entry.online_user.is_some(),
+                Status::Offline => entry.online_user.is_none(),
+            })
+            .collect();
+
+        match options.sort {
+            Sort::Name => entries.sort_by_key(|entry| entry.registered_user.name().to_lowercase()),
+            Sort::Active => {
+                for entry in entries.iter_mut().filter(|entry| entry.online_user.is_none()) {
+                    entry.last_active = last_active(&mut c, server.clone(), entry.registered_user.id()).await;
+                }
+                // online users first, starting with the least idle, then everyone else by when they were last seen
+                entries.sort_by(|a, b| match (a.online_user.as_ref(), b.online_user.as_ref()) {
+                    (Some(a), Some(b)) => a.idle_secs().cmp(&b.idle_secs()),
+                    (Some(_), None) => Ordering::Less,
+                    (None, Some(_)) => Ordering::Greater,
+                    (None, None) => b.last_active.cmp(&a.last_active),
                 });
-                let user_list_message = TextMessage {
-                    server: server,
-                    actor: None,
-                    users: vec![user],
-                    channels: vec![],
-                    trees: vec![],
-                    text: Some(user_list)
-                };
-                drop(c.text_message_send(user_list_message).await);
+            },
+        }
+
+        let pages = ((entries.len() + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+        let page = options.page.min(pages);
+        let mut user_list = format!("<b>Registered users ({}):</b><br/>", entries.len());
+        for entry in entries.into_iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE) {
+            let mut line = match entry.online_user.as_ref() {
+                Some(online_user) => online_entry(online_user, &channel_names),
+                None => {
+                    let last_active = match entry.last_active {
+                        Some(last_active) => Some(last_active),
+                        None => last_active(&mut c, server.clone(), entry.registered_user.id()).await,
+                    };
+                    offline_entry(&entry.registered_user, last_active)
+                },
+            };
+            if options.all && profiles.visibility(entry.registered_user.id()) != Visibility::Public {
+                line.push_str(&format!(" <i>({})</i>", profiles.visibility(entry.registered_user.id()).name()));
+            }
+            user_list.push_str(&line);
+            user_list.push_str("<br/>");
+        }
+        if pages > 1 {
+            user_list.push_str(&format!("<sup>page {} of {}", page, pages));
+            if page < pages {
+                user_list.push_str(&format!(" - type <b>{}</b> for more", escape_html(&options.command(page + 1))));
+            }
+            user_list.push_str("</sup>");
+        }
+        send_reply(&mut c, server, user, user_list).await;
+        false
+    })
+}
+
+// handles `!who`, `!who <channel>` and `!who tree`, which list everyone who is connected, including
+// unregistered users, in a reply to the whole channel of the sender
+pub fn who<D>(_t: DataMutex<D>, mut c: Client, e: &Event) -> FutureBool {
+    let e = e.to_owned();
+    if e.server.is_none() || e.user.is_none() || e.message.is_none() {
+        return future_from_bool(true);
+    }
+    let user = e.user.unwrap();
+    let server = e.server;
+    let message = e.message.as_ref().unwrap().text().trim().to_owned();
+    if message != WHO_KEYWORD && !message.starts_with(&format!("{} ", WHO_KEYWORD)) {
+        return future_from_bool(true);
+    }
+    future_from_async(async move {
+        let argument = message[WHO_KEYWORD.len()..].trim().to_owned();
+        let users = connected_users(&mut c, server.clone()).await;
+        let channels = channels(&mut c, server.clone()).await;
+        let text = if argument == "tree" {
+            let mut tree = format!("<b>Connected users ({}):</b>", users.len());
+            channel_tree(&mut tree, ROOT_CHANNEL, 0, &channels, &users);
+            Some(tree)
+        } else if argument.is_empty() {
+            user.channel.as_ref()
+                .and_then(|channel| channels.iter().find(|c| c.id() == channel.id()))
+                .map(|channel| channel_line(channel, &users))
+        } else {
+            let name = argument.to_lowercase();
+            match channels.iter().find(|channel| channel.name().to_lowercase() == name) {
+                Some(channel) => Some(channel_line(channel, &users)),
+                None => {
+                    let error = error_html(&format!("Couldn't find a channel with the name `{}`", escape_html(&argument)));
+                    send_reply(&mut c, server, user, error).await;
+                    return false;
+                },
+            }
+        };
+        if let (Some(text), Some(channel)) = (text, user.channel.clone()) {
+            let message = TextMessage {
+                server: server,
+                actor: None,
+                users: vec![],
+                channels: vec![channel],
+                trees: vec![],
+                text: Some(text)
+            };
+            drop(c.text_message_send(message).await);
+        }
+        false
+    })
+}
+
+// e.g. "Lobby (3): alice, bob 🔇, guest" with unregistered users in italics
+fn channel_line(channel: &Channel, users: &[User]) -> String {
+    let names: Vec<String> = users.iter()
+        .filter(|user| user.channel.as_ref().map(|c| c.id()) == Some(channel.id()))
+        .map(who_entry)
+        .collect();
+    let mut line = format!("<b>{} ({}):</b> ", escape_html(channel.name()), names.len());
+    if names.is_empty() {
+        line.push_str("nobody");
+    } else {
+        line.push_str(&names.join(", "));
+    }
+    line
+}
+
+// adds a line for every channel below the given one that has someone in it
+fn channel_tree(tree: &mut String, parent: u32, depth: usize, channels: &[Channel], users: &[User]) -> bool {
+    let mut has_users = false;
+    for channel in channels.iter() {
+        let is_child = if channel.id() == ROOT_CHANNEL {
+            depth == 0
+        } else {
+            depth > 0 && channel.parent.as_ref().map(|c| c.id()) == Some(parent)
+        };
+        if !is_child {
+            continue;
+        }
+        let mut subtree = String::new();
+        let subtree_has_users = channel_tree(&mut subtree, channel.id(), depth + 1, channels, users);
+        let has_own_users = users.iter().any(|user| user.channel.as_ref().map(|c| c.id()) == Some(channel.id()));
+        if has_own_users || subtree_has_users {
+            tree.push_str(&format!("<br/>{}{}", "&nbsp;&nbsp;&nbsp;&nbsp;".repeat(depth), channel_line(channel, users)));
+            tree.push_str(&subtree);
+            has_users = true;
+        }
+    }
+    has_users
+}
+
+fn who_entry(user: &User) -> String {
+    let mut entry = if user.id.is_some() {
+        escape_html(user.name())
+    } else {
+        format!("<i>{}</i>", escape_html(user.name()))
+    };
+    if user.deaf() || user.self_deaf() {
+        entry.push_str(" 🙉");
+    } else if user.mute() || user.self_mute() || user.suppress() {
+        entry.push_str(" 🔇");
+    }
+    entry
+}
+
+// handles `!profile <name>` and `!profile set ...`, which show and change the profile card of a
+// registered user
+pub fn profile(t: DataMutex<Data>, mut c: Client, e: &Event) -> FutureBool {
+    let e = e.to_owned();
+    if e.server.is_none() || e.user.is_none() || e.message.is_none() {
+        return future_from_bool(true);
+    }
+    let user = e.user.unwrap();
+    let server = e.server;
+    let message = e.message.as_ref().unwrap().text().trim().to_owned();
+    if message != PROFILE_KEYWORD && !message.starts_with(&format!("{} ", PROFILE_KEYWORD)) {
+        return future_from_bool(true);
+    }
+    future_from_async(async move {
+        let argument = message[PROFILE_KEYWORD.len()..].trim().to_owned();
+        let server_path = t.lock_async().await.path.to_owned();
+        let mut words = argument.splitn(3, char::is_whitespace);
+        let reply = match (words.next().unwrap_or(""), words.next(), words.next().map(|text| text.trim())) {
+            ("", _, _) => PROFILE_USAGE.to_string(),
+            ("set", setting, value) => {
+                if user.id.is_none() {
+                    error_html("Only registered users have a profile")
+                } else {
+                    let mut profiles = Profiles::load(server_path.join(PROFILES_FILE));
+                    let profile = profiles.users.entry(user.id().to_string()).or_default();
+                    let result = match (setting, value) {
+                        (Some("bio"), None) => {
+                            profile.bio = None;
+                            Ok("Removed your bio")
+                        },
+                        (Some("bio"), Some(bio)) if bio.chars().count() > MAX_BIO_LENGTH =>
+                            Err(format!("Your bio can't be longer than {} characters", MAX_BIO_LENGTH)),
+                        (Some("bio"), Some(bio)) => {
+                            profile.bio = Some(bio.to_owned());
+                            Ok("Updated your bio")
+                        },
+                        (Some("contact"), Some("on")) => {
+                            profile.show_contact = true;
+                            Ok("Your email address is now shown on your profile")
+                        },
+                        (Some("contact"), Some("off")) => {
+                            profile.show_contact = false;
+                            Ok("Your email address is no longer shown on your profile")
+                        },
+                        (Some("visibility"), Some("public")) => {
+                            profile.visibility = Visibility::Public;
+                            Ok("Everyone can now find you in user listings and profiles")
+                        },
+                        (Some("visibility"), Some("registered-only")) => {
+                            profile.visibility = Visibility::RegisteredOnly;
+                            Ok("Only registered users can now find you in user listings and profiles")
+                        },
+                        (Some("visibility"), Some("hidden")) => {
+                            profile.visibility = Visibility::Hidden;
+                            Ok("You are now hidden from user listings and profiles")
+                        },
+                        _ => Err(format!("Unknown setting<br/>{}", PROFILE_USAGE)),
+                    };
+                    match result {
+                        Ok(reply) => match profiles.store(server_path.join(PROFILES_FILE)) {
+                            Ok(_) => reply.to_string(),
+                            Err(_) => error_html("Your profile couldn't be saved"),
+                        },
+                        Err(error) => error_html(&error),
+                    }
+                }
+            },
+            _ => {
+                let profiles = Profiles::load(server_path.join(PROFILES_FILE));
+                let mut database_user = find_database_user(&mut c, server.clone(), &argument).await;
+                if let Some(id) = database_user.as_ref().map(|database_user| database_user.id()) {
+                    // users who can't see the profile are told the same as if it didn't exist
+                    if !profiles.is_visible_to(id, &user) && !is_admin(&mut c, server.clone(), user.clone()).await {
+                        database_user = None;
+                    }
+                }
+                match database_user {
+                    Some(database_user) => {
+                        let profile = profiles.users.get(&database_user.id().to_string()).cloned().unwrap_or_default();
+                        let online_user = online_users(&mut c, server.clone()).await.remove(&database_user.id());
+                        profile_card(&database_user, &profile, online_user.as_ref())
+                    },
+                    None => error_html(&format!("Couldn't find a user with the name `{}`", escape_html(&argument))),
+                }
+            },
+        };
+        send_reply(&mut c, server, user, reply).await;
+        false
+    })
+}
+
+// the registered user with exactly the given name, with all of their information
+async fn find_database_user(c: &mut Client, server: Option<Server>, name: &str) -> Option<DatabaseUser> {
+    let query = Query {
+        server: server.clone(),
+        filter: Some(name.to_owned()),
+    };
+    let users = c.database_user_query(query).await.ok()?.into_inner().users;
+    let id = users.iter().find(|user| user.name().to_lowercase() == name.to_lowercase())?.id();
+    let database_user = DatabaseUser {
+        server: server, id: Some(id), name: None,
+        email: None, comment: None, hash: None,
+        password: None, last_active: None, texture: None};
+    c.database_user_get(database_user).await.ok().map(|response| response.into_inner())
+}
+
+fn profile_card(user: &DatabaseUser, profile: &Profile, online_user: Option<&User>) -> String {
+    let mut card = "<br/><table><tr>".to_string();
+    if let Some(texture) = user.texture.as_ref().filter(|texture| texture.len() <= MAX_AVATAR_BYTES) {
+        if let Some(mime_type) = image_type(texture) {
+            card.push_str(&format!("<td><img height=\"96\" src=\"data:{};base64,{}\"/></td>", mime_type, base64::encode(texture)));
+        }
+    }
+    card.push_str(&format!("<td><h3>👤 {}</h3>", escape_html(user.name())));
+    if let Some(online_user) = online_user {
+        card.push_str(&format!("🟢 online for {}<br/>", duration(online_user.online_secs() as i64)));
+    } else if let Some(last_active) = user.last_active.as_deref().and_then(parse_last_active) {
+        card.push_str(&format!("⚫ last seen {} ago<br/>", duration((Utc::now().naive_utc() - last_active).num_seconds())));
+    }
+    card.push_str(&format!("registered as user #{}<br/>", user.id()));
+    if profile.show_contact {
+        if let Some(email) = user.email.as_ref().filter(|email| !email.is_empty()) {
+            card.push_str(&format!("✉️ {}<br/>", escape_html(email)));
+        }
+    }
+    card.push_str("</td></tr></table>");
+    if let Some(bio) = profile.bio.as_ref() {
+        card.push_str(&format!("<p>{}</p>", escape_html(bio)));
+    }
+    // the comment is set in the mumble client and already is html
+    if let Some(comment) = user.comment.as_ref().filter(|comment| !comment.is_empty()) {
+        if comment.len() <= MAX_COMMENT_BYTES {
+            card.push_str(&format!("<hr/>{}", comment));
+        } else {
+            card.push_str("<hr/><i>The comment is too long to be shown here</i>");
+        }
+    }
+    card
+}
+
+// the mime type of an avatar, which mumble clients can set to a png, jpeg or gif image
+fn image_type(texture: &[u8]) -> Option<&'static str> {
+    if texture.starts_with(b"\x89PNG") {
+        Some("image/png")
+    } else if texture.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if texture.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Profiles {
+    // by user id
+    users: HashMap<String, Profile>,
+}
+
+impl Persistent for Profiles {}
+
+impl Profiles {
+    fn visibility(&self, id: u32) -> Visibility {
+        self.users.get(&id.to_string()).map(|profile| profile.visibility).unwrap_or_default()
+    }
+
+    fn is_visible_to(&self, id: u32, viewer: &User) -> bool {
+        self.visibility(id).is_visible_to(id, viewer.id)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Profile {
+    bio: Option<String>,
+    // whether the email address of the user is shown on their profile card
+    show_contact: bool,
+    visibility: Visibility,
+}
+
+struct Entry {
+    registered_user: DatabaseUser,
+    online_user: Option<User>,
+    last_active: Option<NaiveDateTime>,
+}
+
+enum Sort {
+    Name,
+    Active,
+}
+
+enum Status {
+    Any,
+    Online,
+    Offline,
+}
+
+// the arguments of `!users [filter] [--sort name|active] [--online|--offline] [--page number] [--all]`
+struct Options {
+    filter: Option<String>,
+    sort: Sort,
+    status: Status,
+    page: usize,
+    // also lists users who have hidden themselves, which only admins can do
+    all: bool,
+}
+
+impl Options {
+    fn parse<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<Self, String> {
+        let mut options = Self {
+            filter: None,
+            sort: Sort::Name,
+            status: Status::Any,
+            page: 1,
+            all: false,
+        };
+        let mut filter = vec![];
+        while let Some(word) = words.next() {
+            match word {
+                "--sort" => options.sort = match words.next() {
+                    Some("name") => Sort::Name,
+                    Some("active") => Sort::Active,
+                    _ => return Err("Users can be sorted by <tt>name</tt> or <tt>active</tt>".to_string()),
+                },
+                "--online" => options.status = Status::Online,
+                "--offline" => options.status = Status::Offline,
+                "--all" => options.all = true,
+                "--page" => options.page = match words.next().and_then(|page| page.parse::<usize>().ok()) {
+                    Some(page) if page > 0 => page,
+                    _ => return Err("The page has to be a number".to_string()),
+                },
+                word if word.starts_with("--") => return Err(format!("Unknown option <tt>{}</tt>", escape_html(word))),
+                word => filter.push(word),
             }
-            false
-        });
+        }
+        if !filter.is_empty() {
+            options.filter = Some(filter.join(" "));
+        }
+        Ok(options)
+    }
+
+    // the command that lists the given page with the same options
+    fn command(&self, page: usize) -> String {
+        let mut command = KEYWORD.to_string();
+        if let Some(filter) = self.filter.as_ref() {
+            command.push_str(&format!(" {}", filter));
+        }
+        if let Sort::Active = self.sort {
+            command.push_str(" --sort active");
+        }
+        match self.status {
+            Status::Any => {},
+            Status::Online => command.push_str(" --online"),
+            Status::Offline => command.push_str(" --offline"),
+        }
+        if self.all {
+            command.push_str(" --all");
+        }
+        command.push_str(&format!(" --page {}", page));
+        command
     }
-    future_from_bool(true)
 }
+
+async fn connected_users(c: &mut Client, server: Option<Server>) -> Vec<User> {
+    let query = crate::user::Query {
+        server: server,
+    };
+    match c.user_query(query).await {
+        Ok(response) => response.into_inner().users,
+        Err(_) => vec![],
+    }
+}
+
+// registered users that are currently connected, by user id
+async fn online_users(c: &mut Client, server: Option<Server>) -> HashMap<u32, User> {
+    connected_users(c, server).await.into_iter()
+        .filter_map(|user| user.id.map(|id| (id, user)))
+        .collect()
+}
+
+async fn channels(c: &mut Client, server: Option<Server>) -> Vec<Channel> {
+    let query = crate::channel::Query {
+        server: server,
+    };
+    match c.channel_query(query).await {
+        Ok(response) => response.into_inner().channels,
+        Err(_) => vec![],
+    }
+}
+
+async fn channel_names(c: &mut Client, server: Option<Server>) -> HashMap<u32, String> {
+    channels(c, server).await.into_iter()
+        .map(|channel| (channel.id(), channel.name().to_owned()))
+        .collect()
+}
+
+fn online_entry(user: &User, channel_names: &HashMap<u32, String>) -> String {
+    let mut entry = format!("🟢 <b>{}</b>", escape_html(user.name()));
+    if let Some(channel) = user.channel.as_ref().and_then(|channel| channel_names.get(&channel.id())) {
+        entry.push_str(&format!(" in <i>{}</i>", escape_html(channel)));
+    }
+    if user.deaf() || user.self_deaf() {
+        entry.push_str(" 🙉");
+    } else if user.mute() || user.self_mute() || user.suppress() {
+        entry.push_str(" 🔇");
+    }
+    if user.idle_secs() >= 60 {
+        entry.push_str(&format!(" (idle for {})", duration(user.idle_secs() as i64)));
+    }
+    entry
+}
+
+// the query only returns names and ids, so the last activity has to be looked up for each user
+async fn last_active(c: &mut Client, server: Option<Server>, id: u32) -> Option<NaiveDateTime> {
+    let database_user = DatabaseUser {
+        server: server, id: Some(id), name: None,
+        email: None, comment: None, hash: None,
+        password: None, last_active: None, texture: None};
+    match c.database_user_get(database_user).await {
+        Ok(response) => response.into_inner().last_active.as_deref().and_then(parse_last_active),
+        Err(_) => None,
+    }
+}
+
+fn offline_entry(user: &DatabaseUser, last_active: Option<NaiveDateTime>) -> String {
+    let name = escape_html(user.name());
+    match last_active {
+        Some(last_active) => format!("⚫ {} - last seen {} ago", name, duration((Utc::now().naive_utc() - last_active).num_seconds())),
+        None => format!("⚫ {}", name),
+    }
+}
+
+fn parse_last_active(last_active: &str) -> Option<NaiveDateTime> {
+    LAST_ACTIVE_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(last_active.trim(), format).ok())
+}
+
+// the largest whole unit of a number of seconds, e.g. "3 days"
+fn duration(seconds: i64) -> String {
+    let seconds = seconds.max(0);
+    let (amount, unit) = match seconds {
+        s if s < 60 => (s, "second"),
+        s if s < 60 * 60 => (s / 60, "minute"),
+        s if s < 60 * 60 * 24 => (s / (60 * 60), "hour"),
+        s if s < 60 * 60 * 24 * 365 => (s / (60 * 60 * 24), "day"),
+        s => (s / (60 * 60 * 24 * 365), "year"),
+    };
+    if amount == 1 {
+        format!("1 {}", unit)
+    } else {
+        format!("{} {}s", amount, unit)
+    }
+}
+
+// admins are users who can change the permissions of the root channel. This is the same check as
+// `convenience::is_admin`, which is written for the other client api.
+async fn is_admin(c: &mut Client, server: Option<Server>, user: User) -> bool {
+    let query = crate::acl::Query {
+        server: server.clone(),
+        user: Some(user),
+        channel: Some(Channel {
+            server: server, id: Some(ROOT_CHANNEL), name: None,
+            parent: None, links: vec![], description: None,
+            temporary: None, position: None}),
+    };
+    match c.acl_get_effective_permissions(query).await {
+        Ok(response) => response.into_inner().allow() & (crate::acl::Permission::Write as u32) != 0,
+        Err(_) => false,
+    }
+}
+
+async fn send_reply(c: &mut Client, server: Option<Server>, user: User, text: String) {
+    let message = TextMessage {
+        server: server,
+        actor: None,
+        users: vec![user],
+        channels: vec![],
+        trees: vec![],
+        text: Some(text)
+    };
+    drop(c.text_message_send(message).await);
+}
+